@@ -2,7 +2,9 @@ use rawst_dl::cli::args;
 use rawst_dl::cli::args::Arguments;
 use rawst_dl::cli::args::Command;
 use rawst_dl::core::config::{Config, edit_config};
-use rawst_dl::core::engine::{download, resume_download};
+#[cfg(unix)]
+use rawst_dl::core::daemon::{add, daemon, pause, status};
+use rawst_dl::core::engine::{download, queue, resume_download};
 use rawst_dl::core::errors::RawstErr;
 use rawst_dl::core::history;
 use rawst_dl::core::logger;
@@ -39,7 +41,18 @@ async fn run(config: Config, args: Arguments) -> Result<(), RawstErr> {
             Command::Download(args) => download(args, config).await?,
             Command::Resume(args) => resume_download(args, config).await?,
             Command::History(args) => history::check_history_args(args, config).await?,
+            Command::Queue(args) => queue(args, config).await?,
             Command::Config => edit_config(config).await?,
+            #[cfg(unix)]
+            Command::Daemon(args) => daemon(args, config).await?,
+            #[cfg(unix)]
+            Command::Add(args) => add(args, config).await?,
+            #[cfg(unix)]
+            Command::Pause(args) => pause(args, config).await?,
+            #[cfg(unix)]
+            Command::Status(args) => status(args, config).await?,
+            #[cfg(not(unix))]
+            Command::Daemon(_) | Command::Add(_) | Command::Pause(_) | Command::Status(_) => return Err(RawstErr::DaemonUnsupported),
         }
     }
 
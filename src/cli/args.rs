@@ -1,8 +1,11 @@
 use std::path::PathBuf;
+use std::str::FromStr;
 
+use chrono::{DateTime, Local};
 use directories::BaseDirs;
 use iri_string::types::IriString;
 
+use clap::ArgGroup;
 use clap::Args;
 use clap::CommandFactory;
 use clap::Parser;
@@ -11,6 +14,12 @@ use clap_complete::Generator;
 use clap_complete::Shell;
 use clap_num::number_range;
 
+use crate::core::checksum::Checksum;
+use crate::core::config::{RemoteChangePolicy, StorageMode};
+use crate::core::proxy::{ProxyCredentials, ProxyTarget};
+use crate::core::queue::{parse_start_time, CronSchedule};
+use crate::core::rate_limit::ByteRate;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum InputSource {
     File(PathBuf),
@@ -44,6 +53,11 @@ fn parse_input_source(s: &str) -> Result<InputSource, String> {
 /// - Download
 /// - Resume
 /// - History
+/// - Queue
+/// - Daemon
+/// - Add
+/// - Pause
+/// - Status
 #[derive(Subcommand, Debug, PartialEq)]
 #[command(name = "rawst-subcommand")]
 pub enum Command {
@@ -53,6 +67,16 @@ pub enum Command {
     Resume(ResumeArgs),
     /// Inspect download history
     History(HistoryArgs),
+    /// Inspect and run scheduled downloads
+    Queue(QueueArgs),
+    /// Run downloads in the background, controlled with `add`, `pause`, `resume` and `status`
+    Daemon(DaemonArgs),
+    /// Add downloads to the daemon
+    Add(AddArgs),
+    /// Pause downloads of the daemon
+    Pause(PauseArgs),
+    /// Show the downloads of the daemon
+    Status(StatusArgs),
     /// Edit config settings
     Config,
 }
@@ -61,25 +85,72 @@ pub enum Command {
 // -----------
 
 // Download
-const MAX_DOWNLOAD_THREADS: u8 = 8;
-
+// Only the jobs are queued by `--at`, `--after` and `--cron`, the overrides of the config which apply
+// to a whole run can't be given along with them
 #[derive(Args, Debug, PartialEq)]
+#[command(group(ArgGroup::new("schedule").args(["at", "after", "cron"]).multiple(true)))]
 pub struct DownloadArgs {
     // Configuration
     /// Maximum amount of threads used to download
     ///
-    /// Limited to 8 threads to avoid throttling
+    /// Limited to `max_threads` of the config (8 by default) to avoid throttling, host policies can allow more
     #[arg(
       short,
       long,
-      value_parser=limit_max_download_threads
+      value_parser=at_least_one
     )]
-    pub threads: Option<u8>,
+    pub threads: Option<usize>,
+
+    /// Maximum amount of files downloaded at the same time
+    ///
+    /// Remaining downloads wait in a queue and start as others finish
+    #[arg(long, value_parser=at_least_one, conflicts_with = "schedule")]
+    pub max_downloads: Option<usize>,
+
+    /// Maximum amount of connections opened at the same time across all downloads
+    #[arg(long, value_parser=at_least_one, conflicts_with = "schedule")]
+    pub max_connections: Option<usize>,
+
+    /// Adapts the amount of connections to the measured speed
+    ///
+    /// starts with a few connections and adds more while the download gets faster, `--threads` being the most it opens
+    #[arg(long, action, conflicts_with = "schedule")]
+    pub adaptive: bool,
+
+    /// Bandwidth shared by all downloads, eg. `500K` or `2M` bytes per second
+    ///
+    /// replaces `limit_rate` and the `rate_schedule` of the config, links files can limit each download with `limit-rate=`
+    #[arg(long, value_parser=ByteRate::from_str, conflicts_with = "schedule")]
+    pub limit_rate: Option<ByteRate>,
+
+    /// Proxy of all downloads, eg. `http://127.0.0.1:8080` or `socks5h://127.0.0.1:1080`
+    ///
+    /// replaces `proxy` of the config and the `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` environment variables, `direct` connects without any proxy
+    #[arg(long, value_parser=ProxyTarget::from_str, conflicts_with = "schedule")]
+    pub proxy: Option<ProxyTarget>,
+
+    /// Credentials of the proxy as USER:PASSWORD, whether it comes from `--proxy`, the config or the environment
+    #[arg(long, value_parser=ProxyCredentials::from_str, conflicts_with = "schedule")]
+    pub proxy_user: Option<ProxyCredentials>,
+
+    /// Priority of the downloads, higher ones start first and take connections away from lower ones
+    ///
+    /// links files can set it for each download with `priority=`, which takes precedence
+    #[arg(long, allow_negative_numbers = true)]
+    pub priority: Option<i32>,
+
+    /// Maximum amount of attempts for each segment before the download fails
+    #[arg(long, value_parser=clap::value_parser!(u32).range(1..), conflicts_with = "schedule")]
+    pub retries: Option<u32>,
+
+    /// Delay in milliseconds before the first retry, doubled on every following retry
+    #[arg(long, conflicts_with = "schedule")]
+    pub retry_delay: Option<u64>,
 
     // Inputs
     /// The input source to download from
     /// 
-    /// URL and text file path that contains URLS could be used
+    /// URL, comma separated URLs and text file path that contains URLS could be used
     #[arg(value_parser=parse_input_source, default_value=None)]
     pub input: Option<InputSource>,
 
@@ -89,16 +160,56 @@ pub struct DownloadArgs {
     /// passing only custom file name without PATH will download the file with custom name in the default download directory
     /// 
     /// eg. `foo\bar\custom_name.exe` or `custom_name.exe`
+    ///
+    /// when downloading comma separated IRIs, pass it once for each IRI in the same order
     #[arg(long)]
     pub output_file_path: Vec<PathBuf>,
 
+    /// Where the segments of concurrent downloads are written
+    ///
+    /// `preallocated` avoids the part files and the merge at the end, halving the disk usage and I/O
+    #[arg(long, value_enum, conflicts_with = "schedule")]
+    pub storage: Option<StorageMode>,
+
+    /// Expected checksum of the downloaded file as `<ALGORITHM>:<HEX>`
+    ///
+    /// supported algorithms are sha256, sha512, sha1, md5 and blake3, eg. `sha256:e3b0c442...`
+    ///
+    /// when downloading several files, pass it once for each file in the same order
+    #[arg(long, value_parser=Checksum::from_str)]
+    pub checksum: Vec<Checksum>,
+
+    /// Looks up the expected checksum when none is given
+    ///
+    /// checks the `Repr-Digest`/`Digest` headers, then sidecar files like `file.iso.sha256` and `SHA256SUMS` next to the file
+    #[arg(long, action, conflicts_with = "schedule")]
+    pub auto_checksum: bool,
+
     /// Path to JSON file containing request headers.
     #[arg(long, default_value=None)]
     pub headers_file_path: Option<PathBuf>,
+
+    // Scheduling
+    /// Queues the downloads to start at this local time instead of now, eg. `2026-11-01 02:00`
+    ///
+    /// `rawst queue run` starts them once they are due, with the config of that time.
+    /// Options overriding the config for the whole run can't be given along with it
+    #[arg(long, value_parser=parse_start_time, conflicts_with = "after")]
+    pub at: Option<DateTime<Local>>,
+
+    /// Queues the downloads to start after this long, eg. `3h` or `30min`
+    #[arg(long)]
+    pub after: Option<humantime::Duration>,
+
+    /// Queues the downloads to start on every occurrence of a cron expression, eg. `0 2 * * *` every night at 02:00
+    ///
+    /// the first run is the first occurrence after `--at` or `--after` when given
+    #[arg(long, value_parser=CronSchedule::from_str)]
+    pub cron: Option<CronSchedule>,
 }
 
-fn limit_max_download_threads(s: &str) -> Result<u8, String> {
-    number_range(s, 0, MAX_DOWNLOAD_THREADS)
+fn at_least_one(s: &str) -> Result<usize, String> {
+    number_range(s, 1, usize::MAX)
 }
 
 // Resume
@@ -107,6 +218,142 @@ pub struct ResumeArgs {
     /// The Downloads to resume
     #[arg(default_value="auto")]
     pub download_ids: Vec<String>,
+
+    /// Resume every unfinished download at once
+    ///
+    /// The downloads run concurrently within the limits of `--max-downloads` and `--max-connections` of the config
+    #[arg(long, action, conflicts_with = "download_ids")]
+    pub all: bool,
+
+    /// Only resume downloads started within this long, eg. `2h` or `3days`
+    #[arg(long, requires = "all")]
+    pub since: Option<humantime::Duration>,
+
+    /// Only resume downloads from this host
+    #[arg(long, requires = "all")]
+    pub host: Option<String>,
+
+    /// What to do when a file changed on the server since its download started
+    ///
+    /// Defaults to `on_remote_change` of the config, `abort` unless set
+    #[arg(long, value_enum)]
+    pub on_change: Option<RemoteChangePolicy>,
+
+    /// Bandwidth shared by all resumed downloads, eg. `500K` or `2M` bytes per second
+    ///
+    /// replaces `limit_rate` and the `rate_schedule` of the config
+    #[arg(long, value_parser=ByteRate::from_str)]
+    pub limit_rate: Option<ByteRate>,
+
+    /// Proxy of the resumed downloads, `direct` connects without any proxy
+    ///
+    /// replaces `proxy` of the config and the proxy environment variables
+    #[arg(long, value_parser=ProxyTarget::from_str)]
+    pub proxy: Option<ProxyTarget>,
+
+    /// Credentials of the proxy as USER:PASSWORD, whether it comes from `--proxy`, the config or the environment
+    #[arg(long, value_parser=ProxyCredentials::from_str)]
+    pub proxy_user: Option<ProxyCredentials>,
+}
+
+#[derive(Args, Debug, PartialEq)]
+pub struct QueueArgs {
+    #[command(subcommand)]
+    pub command: QueueCommand,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum QueueCommand {
+    /// Show the scheduled downloads
+    List,
+    /// Start the scheduled downloads which are due
+    Run {
+        /// Keep running and start the other downloads as they become due, until the queue is empty
+        #[arg(long, action)]
+        wait: bool,
+    },
+    /// Change the priority of a scheduled download
+    Priority {
+        id: String,
+        /// Higher ones start first and take connections away from lower ones
+        #[arg(allow_negative_numbers = true)]
+        priority: i32,
+    },
+    /// Keep a scheduled download from starting until it is resumed
+    Pause {
+        id: String,
+    },
+    /// Let a paused download start again once it is due
+    Resume {
+        id: String,
+    },
+    /// Remove a scheduled download from the queue
+    Cancel {
+        id: String,
+    },
+}
+
+// Daemon
+#[derive(Args, Debug, PartialEq)]
+pub struct DaemonArgs {
+    /// Stay attached to the terminal instead of detaching
+    #[arg(long, action)]
+    pub foreground: bool,
+
+    /// Stop the running daemon, its unfinished downloads can be resumed later
+    #[arg(long, action, conflicts_with = "foreground")]
+    pub stop: bool,
+}
+
+// Add
+#[derive(Args, Debug, PartialEq)]
+pub struct AddArgs {
+    /// Maximum amount of threads used by each download
+    #[arg(short, long, value_parser=at_least_one)]
+    pub threads: Option<usize>,
+
+    /// Priority of the downloads, higher ones start first and take connections away from lower ones
+    ///
+    /// links files can set it for each download with `priority=`, which takes precedence
+    #[arg(long, allow_negative_numbers = true)]
+    pub priority: Option<i32>,
+
+    /// The input source to download from
+    ///
+    /// URL, comma separated URLs and text file path that contains URLS could be used
+    #[arg(value_parser=parse_input_source)]
+    pub input: InputSource,
+
+    /// PATH where the files are downloaded along with custom file name, once for each IRI in the same order
+    #[arg(long)]
+    pub output_file_path: Vec<PathBuf>,
+
+    /// Expected checksum of each downloaded file as `<ALGORITHM>:<HEX>`, once for each IRI in the same order
+    #[arg(long, value_parser=Checksum::from_str)]
+    pub checksum: Vec<Checksum>,
+
+    /// Path to JSON file containing request headers.
+    #[arg(long, default_value=None)]
+    pub headers_file_path: Option<PathBuf>,
+}
+
+// Pause
+#[derive(Args, Debug, PartialEq)]
+pub struct PauseArgs {
+    /// The downloads to pause
+    #[arg(required_unless_present = "all")]
+    pub ids: Vec<String>,
+
+    /// Pause every running and waiting download
+    #[arg(long, action, conflicts_with = "ids")]
+    pub all: bool,
+}
+
+// Status
+#[derive(Args, Debug, PartialEq)]
+pub struct StatusArgs {
+    /// Only show this download
+    pub id: Option<String>,
 }
 
 #[derive(Args, Debug, PartialEq)]
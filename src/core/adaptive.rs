@@ -0,0 +1,185 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::core::errors::RawstErr;
+use crate::core::task::Chunk;
+
+/// Decides how many connections a concurrent download opens when they adapt to the measured speed
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AdaptivePolicy {
+    /// Adapts the amount of connections instead of always opening `threads` of them
+    pub enabled: bool,
+    /// Connections opened at the start of a download
+    pub initial_connections: usize,
+    /// Time between two throughput measurements
+    pub interval_ms: u64,
+    /// Relative speed increase a new connection has to bring for another one to be added (0.1 = 10%)
+    pub min_gain: f64,
+    /// Time after which a connection receiving nothing counts as stalled
+    pub stall_timeout_ms: u64,
+}
+
+impl Default for AdaptivePolicy {
+    fn default() -> Self {
+        AdaptivePolicy {
+            enabled: false,
+            initial_connections: 2,
+            interval_ms: 1000,
+            min_gain: 0.1,
+            stall_timeout_ms: 10_000,
+        }
+    }
+}
+
+/// Throughput measured on the previous tick
+struct Measurement {
+    total_downloaded: u64,
+    speed: f64,
+    // Whether the last change added a connection, the next tick tells if it was worth it
+    added: bool,
+    // Downloaded bytes of every chunk and when they last changed, by chunk index
+    progress: Vec<(u64, Instant)>,
+}
+
+/// Amount of connections of a download, adjusted from the throughput measured on every tick
+///
+/// Connections are added one at a time while each one makes the download noticeably faster.
+/// Once one doesn't, or a connection stalls, one is taken away. Rate limiting by the server
+/// (429 or 503) also takes one away and the download never grows back to that amount
+pub struct ConnectionController {
+    policy: AdaptivePolicy,
+    ceiling: AtomicUsize,
+    target: AtomicUsize,
+    // Workers asked to stop after their current chunk
+    retirements: AtomicUsize,
+    throttled: AtomicBool,
+    exhausted: AtomicBool,
+    measurement: Mutex<Measurement>,
+}
+
+impl ConnectionController {
+    pub fn new(policy: &AdaptivePolicy, ceiling: usize) -> Self {
+        let ceiling = ceiling.max(1);
+
+        ConnectionController {
+            policy: policy.clone(),
+            ceiling: AtomicUsize::new(ceiling),
+            target: AtomicUsize::new(policy.initial_connections.clamp(1, ceiling)),
+            retirements: AtomicUsize::new(0),
+            throttled: AtomicBool::new(false),
+            exhausted: AtomicBool::new(false),
+            measurement: Mutex::new(Measurement {
+                total_downloaded: 0,
+                speed: 0.0,
+                added: false,
+                progress: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.policy.interval_ms.max(1))
+    }
+
+    /// Amount of connections the download should have right now
+    pub fn target(&self) -> usize {
+        self.target.load(Ordering::SeqCst)
+    }
+
+    /// Notes the errors telling that the server wants fewer connections
+    pub fn report_error(&self, err: &RawstErr) {
+        if matches!(err.status(), Some(StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE)) {
+            self.throttled.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Notes that a worker found nothing left to download
+    pub fn set_exhausted(&self) {
+        self.exhausted.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether every remaining byte of the file already has a worker
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::SeqCst)
+    }
+
+    /// Asks the workers above the target to stop once their current chunk is done
+    pub fn request_retirements(&self, workers: usize) {
+        self.retirements.store(workers.saturating_sub(self.target()), Ordering::SeqCst);
+    }
+
+    /// Called by a worker between two chunks, whether it should stop
+    pub fn take_retirement(&self) -> bool {
+        self.retirements
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |retirements| retirements.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Measures the throughput since the previous tick and adjusts the target accordingly
+    pub fn adjust(&self, total_downloaded: u64, chunks: &[Arc<Chunk>]) -> usize {
+        let mut measurement = self.measurement.lock().unwrap();
+        let now = Instant::now();
+
+        let speed = total_downloaded.saturating_sub(measurement.total_downloaded) as f64 / self.interval().as_secs_f64();
+        measurement.total_downloaded = total_downloaded;
+
+        // Chunks split off since the previous tick start with no progress
+        measurement.progress.resize(chunks.len(), (0, now));
+
+        let mut stalled = false;
+
+        for (chunk_number, chunk) in chunks.iter().enumerate() {
+            let downloaded = chunk.downloaded.load(Ordering::SeqCst);
+            let (previous, changed_at) = &mut measurement.progress[chunk_number];
+
+            if downloaded != *previous {
+                log::trace!("Chunk number {chunk_number} is downloading at {:.0} B/s", (downloaded - *previous) as f64 / self.interval().as_secs_f64());
+
+                *previous = downloaded;
+                *changed_at = now;
+            } else if chunk.in_progress() && now.duration_since(*changed_at) >= Duration::from_millis(self.policy.stall_timeout_ms) {
+                stalled = true;
+                // Counted once, a chunk that stays stalled takes another connection away after a whole timeout
+                *changed_at = now;
+            }
+        }
+
+        let target = self.target();
+        let ceiling = self.ceiling.load(Ordering::SeqCst);
+
+        let new_target = if self.throttled.swap(false, Ordering::SeqCst) {
+            let new_target = target.saturating_sub(1).max(1);
+            self.ceiling.store(new_target, Ordering::SeqCst);
+            log::warn!("The server is rate limiting, going down to {new_target} connections");
+
+            new_target
+        } else if stalled {
+            log::warn!("A connection stalled, going down to {} connections", target.saturating_sub(1).max(1));
+
+            target.saturating_sub(1).max(1)
+        } else if measurement.added && speed < measurement.speed * (1.0 + self.policy.min_gain) {
+            // The last connection didn't pay off, more of them won't either
+            self.ceiling.store(target.saturating_sub(1).max(1), Ordering::SeqCst);
+            log::debug!("{speed:.0} B/s with {target} connections isn't faster, going back to {}", target.saturating_sub(1).max(1));
+
+            target.saturating_sub(1).max(1)
+        } else if target < ceiling {
+            log::debug!("{speed:.0} B/s with {target} connections, adding one");
+
+            target + 1
+        } else {
+            target
+        };
+
+        measurement.added = new_target > target;
+        measurement.speed = speed;
+        self.target.store(new_target, Ordering::SeqCst);
+
+        new_target
+    }
+}
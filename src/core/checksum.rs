@@ -0,0 +1,379 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use base64::{prelude::BASE64_STANDARD, Engine as Base64Engine};
+use md5::Md5;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::fs::{rename, File};
+use tokio::io::AsyncReadExt;
+
+use crate::core::errors::RawstErr;
+use crate::core::task::HttpTask;
+
+/// Size of the buffer used to read the downloaded file
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Sha512,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// Length of the digest in hexadecimal characters
+    fn hex_length(&self) -> usize {
+        match self {
+            ChecksumAlgorithm::Sha256 => 64,
+            ChecksumAlgorithm::Sha512 => 128,
+            ChecksumAlgorithm::Sha1 => 40,
+            ChecksumAlgorithm::Md5 => 32,
+            ChecksumAlgorithm::Blake3 => 64,
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "sha512" => Ok(ChecksumAlgorithm::Sha512),
+            "sha1" => Ok(ChecksumAlgorithm::Sha1),
+            "md5" => Ok(ChecksumAlgorithm::Md5),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            _ => Err(format!("unsupported checksum algorithm '{s}', expected one of sha256, sha512, sha1, md5, blake3")),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChecksumAlgorithm::Sha256 => write!(f, "sha256"),
+            ChecksumAlgorithm::Sha512 => write!(f, "sha512"),
+            ChecksumAlgorithm::Sha1 => write!(f, "sha1"),
+            ChecksumAlgorithm::Md5 => write!(f, "md5"),
+            ChecksumAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+/// A digest along with the algorithm it was computed with, written as `<algorithm>:<hex digest>`
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: String,
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, digest) = s
+            .split_once(':')
+            .ok_or(format!("expected `<algorithm>:<hex digest>`, found '{s}'"))?;
+
+        let algorithm = algorithm.parse::<ChecksumAlgorithm>()?;
+        let digest = digest.trim().to_lowercase();
+
+        if digest.len() != algorithm.hex_length() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("'{digest}' is not a valid {algorithm} digest"));
+        }
+
+        Ok(Checksum { algorithm, digest })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.digest)
+    }
+}
+
+impl TryFrom<String> for Checksum {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Checksum> for String {
+    fn from(checksum: Checksum) -> Self {
+        checksum.to_string()
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Sha1(Sha1),
+    Md5(Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            ChecksumAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            ChecksumAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            ChecksumAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Sha512(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Sha1(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Md5(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Computes the checksum of a file, reading it in bounded chunks
+pub async fn compute_checksum(file_path: &Path, algorithm: ChecksumAlgorithm) -> Result<Checksum, RawstErr> {
+    let mut file = File::open(file_path).await.map_err(RawstErr::FileError)?;
+
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+
+    loop {
+        let read = file.read(&mut buffer).await.map_err(RawstErr::FileError)?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(Checksum {
+        algorithm,
+        digest: hasher.finalize(),
+    })
+}
+
+/// Verifies a finished download against its expected checksum
+///
+/// On mismatch the file is renamed with a `.corrupt` extension so it can't be mistaken for a good one
+pub async fn verify_checksum(task: &HttpTask, expected: &Checksum) -> Result<Checksum, RawstErr> {
+    let file_path = task.download_dir.join(&task.filename);
+
+    let actual = compute_checksum(&file_path, expected.algorithm).await?;
+
+    if actual.digest != expected.digest {
+        let corrupt_file_path = file_path.with_added_extension("corrupt");
+        rename(&file_path, &corrupt_file_path).await.map_err(RawstErr::FileError)?;
+
+        return Err(RawstErr::ChecksumMismatch(expected.to_string(), actual.to_string(), corrupt_file_path));
+    }
+
+    Ok(actual)
+}
+
+/// Finds the expected checksum in the `Repr-Digest`, `Content-Digest` (RFC 9530) or `Digest` (RFC 3230) headers
+///
+/// Returns the checksum along with the header it came from
+pub fn checksum_from_digest_headers(headers: &HeaderMap) -> Option<(Checksum, String)> {
+    for header in ["repr-digest", "content-digest", "digest"] {
+        let Some(value) = headers.get(header).and_then(|value| value.to_str().ok()) else {
+            continue;
+        };
+
+        // eg. `sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:` or `SHA-256=X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=`
+        for entry in value.split(',') {
+            let Some((algorithm, encoded_digest)) = entry.trim().split_once('=') else {
+                continue;
+            };
+
+            let algorithm = match algorithm.trim().to_lowercase().as_str() {
+                "sha-256" => ChecksumAlgorithm::Sha256,
+                "sha-512" => ChecksumAlgorithm::Sha512,
+                "sha" => ChecksumAlgorithm::Sha1,
+                "md5" => ChecksumAlgorithm::Md5,
+                _ => continue,
+            };
+
+            let Ok(digest) = BASE64_STANDARD.decode(encoded_digest.trim().trim_matches(':')) else {
+                continue;
+            };
+
+            let digest = digest.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+
+            if let Ok(checksum) = format!("{algorithm}:{digest}").parse::<Checksum>() {
+                return Some((checksum, format!("{header} header")));
+            }
+        }
+    }
+
+    None
+}
+
+/// Finds the checksum of `file_name` in a `file.sha256` sidecar or a `SHA256SUMS` like file
+///
+/// Lines are expected as `<hex digest>  <file name>`, a lone digest is accepted as well
+pub fn checksum_from_sums_file(content: &str, algorithm: ChecksumAlgorithm, file_name: &str) -> Option<Checksum> {
+    let mut lone_digests = Vec::new();
+
+    for line in content.lines() {
+        let mut parts = line.split_whitespace();
+
+        let Some(digest) = parts.next() else {
+            continue;
+        };
+
+        let Ok(checksum) = format!("{algorithm}:{digest}").parse::<Checksum>() else {
+            continue;
+        };
+
+        // The `*` marks binary mode in the output of sha256sum
+        match parts.next().map(|name| name.trim_start_matches('*').trim_start_matches("./")) {
+            Some(name) if name == file_name => return Some(checksum),
+            Some(_) => (),
+            None => lone_digests.push(checksum),
+        }
+    }
+
+    match lone_digests.len() {
+        1 => lone_digests.pop(),
+        _ => None,
+    }
+}
+
+/// Sibling files that may publish the checksum of `file_name`, along with their algorithm
+pub fn checksum_sidecar_names(file_name: &str) -> Vec<(String, ChecksumAlgorithm)> {
+    vec![
+        (format!("{file_name}.sha256"), ChecksumAlgorithm::Sha256),
+        ("SHA256SUMS".to_string(), ChecksumAlgorithm::Sha256),
+        (format!("{file_name}.sha512"), ChecksumAlgorithm::Sha512),
+        ("SHA512SUMS".to_string(), ChecksumAlgorithm::Sha512),
+        (format!("{file_name}.md5"), ChecksumAlgorithm::Md5),
+        ("MD5SUMS".to_string(), ChecksumAlgorithm::Md5),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    const SHA256_HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const SHA256_WORLD: &str = "486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7";
+
+    fn sha256(digest: &str) -> Checksum {
+        format!("sha256:{digest}").parse().unwrap()
+    }
+
+    #[test]
+    fn finds_the_line_of_the_file() {
+        let content = format!("{SHA256_WORLD}  world.txt\n{SHA256_HELLO}  hello.txt\n");
+
+        assert_eq!(checksum_from_sums_file(&content, ChecksumAlgorithm::Sha256, "hello.txt"), Some(sha256(SHA256_HELLO)));
+        assert_eq!(checksum_from_sums_file(&content, ChecksumAlgorithm::Sha256, "world.txt"), Some(sha256(SHA256_WORLD)));
+        assert_eq!(checksum_from_sums_file(&content, ChecksumAlgorithm::Sha256, "other.txt"), None);
+    }
+
+    #[test]
+    fn matches_binary_mode_and_relative_file_names() {
+        let content = format!("{SHA256_WORLD} *world.txt\n{SHA256_HELLO}  ./hello.txt\n");
+
+        assert_eq!(checksum_from_sums_file(&content, ChecksumAlgorithm::Sha256, "world.txt"), Some(sha256(SHA256_WORLD)));
+        assert_eq!(checksum_from_sums_file(&content, ChecksumAlgorithm::Sha256, "hello.txt"), Some(sha256(SHA256_HELLO)));
+    }
+
+    #[test]
+    fn accepts_uppercase_hex() {
+        let content = format!("{}  hello.txt", SHA256_HELLO.to_uppercase());
+
+        assert_eq!(checksum_from_sums_file(&content, ChecksumAlgorithm::Sha256, "hello.txt"), Some(sha256(SHA256_HELLO)));
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let content = format!(
+            "# SHA256 checksums\n\n{}  hello.txt\n{}zz  hello.txt\nnot a digest  hello.txt\n{SHA256_HELLO}  hello.txt\n",
+            &SHA256_WORLD[..40],
+            &SHA256_WORLD[..62],
+        );
+
+        assert_eq!(checksum_from_sums_file(&content, ChecksumAlgorithm::Sha256, "hello.txt"), Some(sha256(SHA256_HELLO)));
+    }
+
+    #[test]
+    fn skips_digests_of_another_algorithm() {
+        let content = "5d41402abc4b2a76b9719d911017c592  hello.txt\n";
+
+        assert_eq!(checksum_from_sums_file(content, ChecksumAlgorithm::Sha256, "hello.txt"), None);
+        assert!(checksum_from_sums_file(content, ChecksumAlgorithm::Md5, "hello.txt").is_some());
+    }
+
+    #[test]
+    fn takes_a_lone_digest_only_when_there_is_one() {
+        assert_eq!(checksum_from_sums_file(&format!("{SHA256_HELLO}\n"), ChecksumAlgorithm::Sha256, "hello.txt"), Some(sha256(SHA256_HELLO)));
+
+        let content = format!("{SHA256_HELLO}\n{SHA256_WORLD}\n");
+        assert_eq!(checksum_from_sums_file(&content, ChecksumAlgorithm::Sha256, "hello.txt"), None);
+    }
+
+    #[test]
+    fn prefers_repr_digest_over_digest() {
+        let mut headers = HeaderMap::new();
+        headers.insert("digest", HeaderValue::from_static("SHA-256=SG6kYiTRu0+2gPNPfJrZao8k7Ii+c+qOWmxlJg6cuKc="));
+        headers.insert("repr-digest", HeaderValue::from_static("sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:"));
+
+        let (checksum, source) = checksum_from_digest_headers(&headers).unwrap();
+
+        assert_eq!(checksum, sha256(SHA256_HELLO));
+        assert_eq!(source, "repr-digest header");
+    }
+
+    #[test]
+    fn skips_unsupported_and_malformed_digests() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "digest",
+            HeaderValue::from_static("unixsum=30637, sha-256=not base64, SHA-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="),
+        );
+
+        assert_eq!(checksum_from_digest_headers(&headers), Some((sha256(SHA256_HELLO), "digest header".to_string())));
+
+        // Too short for a sha-256 digest
+        headers.insert("digest", HeaderValue::from_static("sha-256=SGVsbG8="));
+        assert_eq!(checksum_from_digest_headers(&headers), None);
+    }
+
+    #[test]
+    fn parses_checksums_in_any_case() {
+        let checksum: Checksum = format!("SHA-256:{}", SHA256_HELLO.to_uppercase()).parse().unwrap();
+
+        assert_eq!(checksum, sha256(SHA256_HELLO));
+        assert!(format!("sha256:{}", &SHA256_HELLO[..63]).parse::<Checksum>().is_err());
+        assert!("crc32:3610a686".parse::<Checksum>().is_err());
+    }
+}
@@ -0,0 +1,1178 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::{prelude::BASE64_STANDARD, Engine as Base64Engine};
+use chrono::Local;
+use indicatif::HumanBytes;
+use iri_string::types::IriString;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::cli::args::{AddArgs, DaemonArgs, InputSource, PauseArgs, ResumeArgs, StatusArgs};
+use crate::core::config::Config;
+use crate::core::engine::{DownloadProgress, Engine, ResumeFilter, QUEUE_POLL_INTERVAL};
+use crate::core::errors::RawstErr;
+use crate::core::history::{HistoryManager, Record};
+use crate::core::io::read_links;
+use crate::core::queue::QueueManager;
+use crate::core::rpc::{ControlClient, Request, Response, RpcError};
+use crate::core::task::DownloadJob;
+use crate::core::utils::{headers_from_file, parse_link_line, resolve_output_path, LinkOptions};
+
+/// Methods of the control API, named after those of aria2
+const METHODS: [&str; 19] = [
+    "aria2.addUri",
+    "aria2.pause",
+    "aria2.forcePause",
+    "aria2.pauseAll",
+    "aria2.forcePauseAll",
+    "aria2.unpause",
+    "aria2.unpauseAll",
+    "aria2.remove",
+    "aria2.forceRemove",
+    "aria2.removeDownloadResult",
+    "aria2.purgeDownloadResult",
+    "aria2.tellStatus",
+    "aria2.tellActive",
+    "aria2.tellWaiting",
+    "aria2.tellStopped",
+    "aria2.getGlobalStat",
+    "aria2.getVersion",
+    "aria2.shutdown",
+    "system.listMethods",
+];
+
+/// How long `rawst daemon` waits for the detached daemon to listen, and for it to stop with `--stop`
+const DAEMON_WAIT_ATTEMPTS: usize = 100;
+const DAEMON_WAIT_DELAY: Duration = Duration::from_millis(100);
+
+/// Most waiting and stopped downloads `rawst status` asks for
+const STATUS_LIMIT: usize = 1000;
+
+pub async fn daemon(args: DaemonArgs, config: Config) -> Result<(), RawstErr> {
+    if args.stop {
+        connect(&config).await?.call("aria2.shutdown", Vec::new()).await?;
+
+        // The socket goes away once the running downloads are stopped
+        for _ in 0..DAEMON_WAIT_ATTEMPTS {
+            if ControlClient::connect(&config).await?.is_none() {
+                println!("Daemon stopped");
+
+                return Ok(());
+            }
+
+            tokio::time::sleep(DAEMON_WAIT_DELAY).await;
+        }
+
+        println!("Daemon is stopping");
+
+        return Ok(());
+    }
+
+    if ControlClient::connect(&config).await?.is_some() {
+        return Err(RawstErr::DaemonRunning(config.socket_path()));
+    }
+
+    match args.foreground {
+        true => serve(config).await,
+        false => detach(&config).await,
+    }
+}
+
+pub async fn add(args: AddArgs, config: Config) -> Result<(), RawstErr> {
+    let mut client = connect(&config).await?;
+
+    // Every download is sent with the options of a links file line, the daemon makes its job the same way
+    let mut shared_options = Map::new();
+
+    if let Some(threads) = args.threads {
+        shared_options.insert("threads".to_string(), Value::from(threads.to_string()));
+    }
+
+    if let Some(priority) = args.priority {
+        shared_options.insert("priority".to_string(), Value::from(priority.to_string()));
+    }
+
+    if let Some(headers_file_path) = args.headers_file_path {
+        let headers = headers_from_file(headers_file_path)?
+            .into_iter()
+            .map(|(name, value)| Value::from(format!("{name}: {value}")))
+            .collect();
+
+        shared_options.insert("header".to_string(), Value::Array(headers));
+    }
+
+    let mut links = match args.input {
+        InputSource::File(file_path) => links_from_file(&file_path).await?,
+        InputSource::Iris(list_of_iris) => list_of_iris.into_iter().map(|iri| (iri, LinkOptions::new())).collect(),
+    };
+
+    if links.is_empty() {
+        return Err(RawstErr::InvalidArgs);
+    }
+
+    // Paired positionally with the downloads, the same way as `rawst download` does
+    if !args.output_file_path.is_empty() {
+        if args.output_file_path.len() != links.len() {
+            return Err(RawstErr::MismatchedOutputs(links.len(), args.output_file_path.len()));
+        }
+
+        for ((_, options), save_as) in links.iter_mut().zip(args.output_file_path) {
+            options.insert("out".to_string(), save_as.display().to_string());
+        }
+    }
+
+    if !args.checksum.is_empty() {
+        if args.checksum.len() != links.len() {
+            return Err(RawstErr::MismatchedChecksums(links.len(), args.checksum.len()));
+        }
+
+        for ((_, options), checksum) in links.iter_mut().zip(args.checksum) {
+            options.insert("checksum".to_string(), checksum.to_string());
+        }
+    }
+
+    for (iri, options) in links {
+        let mut job_options = shared_options.clone();
+
+        for (key, value) in options {
+            // The daemon runs in another directory, relative output paths are resolved in this one
+            let value = match key.as_str() {
+                "out" => resolve_output_path(Path::new(&value))?.display().to_string(),
+                _ => value,
+            };
+
+            job_options.insert(key, Value::from(value));
+        }
+
+        let gid = client
+            .call("aria2.addUri", vec![json!([iri.to_string()]), Value::Object(job_options)])
+            .await?;
+
+        println!("Added {} (id: {})", iri, gid.as_str().unwrap_or_default());
+    }
+
+    Ok(())
+}
+
+pub async fn pause(args: PauseArgs, config: Config) -> Result<(), RawstErr> {
+    let mut client = connect(&config).await?;
+
+    if args.all {
+        client.call("aria2.pauseAll", Vec::new()).await?;
+        println!("Paused all downloads");
+
+        return Ok(());
+    }
+
+    for id in args.ids {
+        client.call("aria2.pause", vec![Value::from(id.clone())]).await?;
+        println!("Paused {id}");
+    }
+
+    Ok(())
+}
+
+pub async fn status(args: StatusArgs, config: Config) -> Result<(), RawstErr> {
+    let mut client = connect(&config).await?;
+
+    let downloads = match args.id {
+        Some(id) => vec![client.call("aria2.tellStatus", vec![Value::from(id)]).await?],
+        None => {
+            let mut downloads = Vec::new();
+
+            for (method, params) in [
+                ("aria2.tellActive", Vec::new()),
+                ("aria2.tellWaiting", vec![Value::from(0), Value::from(STATUS_LIMIT)]),
+                ("aria2.tellStopped", vec![Value::from(0), Value::from(STATUS_LIMIT)]),
+            ] {
+                if let Value::Array(found) = client.call(method, params).await? {
+                    downloads.extend(found);
+                }
+            }
+
+            downloads
+        }
+    };
+
+    if downloads.is_empty() {
+        println!("No downloads in the daemon");
+
+        return Ok(());
+    }
+
+    print_status(&downloads);
+
+    Ok(())
+}
+
+/// Hands `rawst resume` over to the daemon when one is running, whether there is one
+///
+/// The daemon downloads with its own config, the overrides of the command don't reach it
+pub async fn resume_in_daemon(args: &ResumeArgs, config: &Config) -> Result<bool, RawstErr> {
+    let Some(mut client) = ControlClient::connect(config).await? else {
+        return Ok(false);
+    };
+
+    if args.on_change.is_some() || args.limit_rate.is_some() || args.proxy.is_some() || args.proxy_user.is_some() {
+        println!("Warning!: --on-change, --limit-rate and --proxy don't apply to the daemon, it uses its own config");
+    }
+
+    let history_manager = HistoryManager::new(config.history_file_path.clone());
+
+    let ids = if args.all {
+        let filter = ResumeFilter {
+            since: args.since.map(|since| *since),
+            host: args.host.clone(),
+        };
+
+        // Downloads paused before they started aren't in the history yet
+        if filter.since.is_none() && filter.host.is_none() {
+            client.call("aria2.unpauseAll", Vec::new()).await?;
+        }
+
+        // Downloads without cache files start from the beginning, the same as when they are resumed on their own
+        history_manager
+            .get_pending()?
+            .into_iter()
+            .filter(|record| filter.matches(record))
+            .map(|record| record.id)
+            .collect()
+    } else if args.download_ids == ["auto"] {
+        match history_manager.get_recent_pending()? {
+            Some(record) => vec![record.id],
+            None => Vec::new(),
+        }
+    } else {
+        args.download_ids.clone()
+    };
+
+    if ids.is_empty() && !args.all {
+        println!("No downloads to resume");
+    }
+
+    for id in ids {
+        client.call("aria2.unpause", vec![Value::from(id.clone())]).await?;
+        println!("Resumed {id}");
+    }
+
+    Ok(true)
+}
+
+async fn connect(config: &Config) -> Result<ControlClient, RawstErr> {
+    ControlClient::connect(config).await?.ok_or(RawstErr::NoDaemon)
+}
+
+/// Reads a links file for `rawst add`, malformed lines are reported and skipped
+async fn links_from_file(file_path: &std::path::PathBuf) -> Result<Vec<(IriString, LinkOptions)>, RawstErr> {
+    let link_string = read_links(file_path).await?;
+
+    let mut links = Vec::new();
+
+    for (line_number, line) in link_string.lines().enumerate() {
+        match parse_link_line(line) {
+            Ok(Some(link)) => links.push(link),
+            Ok(None) => (),
+            Err(err) => println!("Warning!: Skipping line {} of {}: {err}", line_number + 1, file_path.display()),
+        }
+    }
+
+    Ok(links)
+}
+
+fn print_status(downloads: &[Value]) {
+    let rows: Vec<[String; 5]> = downloads.iter().map(status_row).collect();
+
+    let id_width = rows.iter().map(|row| row[0].len()).max().unwrap_or(0).max("ID".len());
+    let progress_width = rows.iter().map(|row| row[2].len()).max().unwrap_or(0).max("Progress".len());
+    let speed_width = rows.iter().map(|row| row[3].len()).max().unwrap_or(0).max("Speed".len());
+
+    println!("\n{:<id_width$} | {:<8} | {:<progress_width$} | {:<speed_width$} | File", "ID", "Status", "Progress", "Speed");
+    println!("{:-<id_width$}-+-{:-<8}-+-{:-<progress_width$}-+-{:-<speed_width$}-+-{:-<7}", "", "", "", "", "");
+
+    for (download, [id, status, progress, speed, file]) in downloads.iter().zip(rows) {
+        println!("{id:<id_width$} | {status:<8} | {progress:<progress_width$} | {speed:<speed_width$} | {file}");
+
+        if let Some(error) = download["errorMessage"].as_str() {
+            println!("{:<id_width$}   {error}", "");
+        }
+    }
+}
+
+fn status_row(download: &Value) -> [String; 5] {
+    let length = |key: &str| download[key].as_str().and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+
+    let (total, completed, speed) = (length("totalLength"), length("completedLength"), length("downloadSpeed"));
+    let status = download["status"].as_str().unwrap_or_default().to_string();
+
+    let progress = match total {
+        0 => HumanBytes(completed).to_string(),
+        _ => format!("{}% of {}", completed * 100 / total, HumanBytes(total)),
+    };
+
+    let speed = match status.as_str() {
+        "active" => format!("{}/s", HumanBytes(speed)),
+        _ => "-".to_string(),
+    };
+
+    // Until it starts, only the IRI of a download is known
+    let file = &download["files"][0];
+    let file = match file["path"].as_str() {
+        Some(path) if !path.is_empty() => path,
+        _ => file["uris"][0]["uri"].as_str().unwrap_or_default(),
+    };
+
+    [download["gid"].as_str().unwrap_or_default().to_string(), status, progress, speed, file.to_string()]
+}
+
+/// Starts the daemon again as a process of its own, which outlives the terminal
+async fn detach(config: &Config) -> Result<(), RawstErr> {
+    let log_path = config.daemon_log_path();
+
+    let log_file = std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .map_err(RawstErr::FileError)?;
+
+    let executable = std::env::current_exe().map_err(RawstErr::FileError)?;
+
+    let mut child = std::process::Command::new(executable)
+        .args(std::env::args_os().skip(1))
+        .arg("--foreground")
+        .stdin(Stdio::null())
+        .stdout(log_file.try_clone().map_err(RawstErr::FileError)?)
+        .stderr(log_file)
+        // Out of the process group of the terminal, which is stopped along with it
+        .process_group(0)
+        .spawn()
+        .map_err(RawstErr::FileError)?;
+
+    // The daemon is ready once it accepts connections
+    for _ in 0..DAEMON_WAIT_ATTEMPTS {
+        if ControlClient::connect(config).await?.is_some() {
+            println!("Daemon started (pid {}), listening on {}", child.id(), config.socket_path().display());
+
+            return Ok(());
+        }
+
+        if let Some(status) = child.try_wait().map_err(RawstErr::FileError)? {
+            return Err(RawstErr::DaemonError(format!("the daemon exited with {status}, see {}", log_path.display())));
+        }
+
+        tokio::time::sleep(DAEMON_WAIT_DELAY).await;
+    }
+
+    Err(RawstErr::DaemonError(format!("the daemon didn't start listening, see {}", log_path.display())))
+}
+
+/// Runs the daemon until it is asked to stop, or gets SIGINT or SIGTERM
+async fn serve(config: Config) -> Result<(), RawstErr> {
+    let socket_path = config.socket_path();
+
+    // Nobody else can reach the socket through its directory, not even before its own permissions are set
+    create_private_dir(&config.socket_dir())?;
+
+    // Left behind by a daemon which didn't stop cleanly, nothing listens on it anymore
+    match std::fs::remove_file(&socket_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(RawstErr::FileError(err)),
+        _ => (),
+    }
+
+    let listener = UnixListener::bind(&socket_path).map_err(RawstErr::FileError)?;
+
+    // Only the user running the daemon may control it
+    std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(0o600)).map_err(RawstErr::FileError)?;
+
+    let mut interrupt = signal(SignalKind::interrupt()).map_err(RawstErr::FileError)?;
+    let mut terminate = signal(SignalKind::terminate()).map_err(RawstErr::FileError)?;
+    let mut hangup = signal(SignalKind::hangup()).map_err(RawstErr::FileError)?;
+
+    let daemon = Daemon::new(config);
+    let queue = tokio::spawn(daemon.clone().run_queue());
+
+    println!("Listening on {}", socket_path.display());
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let daemon = daemon.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(err) = daemon.handle_connection(stream).await {
+                            log::debug!("Control connection closed: {err}");
+                        }
+                    });
+                }
+                Err(err) => log::warn!("Couldn't accept a control connection: {err}"),
+            },
+            // The daemon outlives the terminal it was started from
+            _ = hangup.recv() => log::info!("Ignoring SIGHUP"),
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+            _ = daemon.shutdown.notified() => break,
+        }
+    }
+
+    queue.abort();
+    daemon.stop().await;
+
+    let _ = std::fs::remove_file(&socket_path);
+
+    Ok(())
+}
+
+/// Creates a directory only its owner may enter, an existing one is taken away from everybody else
+fn create_private_dir(path: &Path) -> Result<(), RawstErr> {
+    match std::fs::DirBuilder::new().mode(0o700).create(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700)).map_err(RawstErr::FileError)
+        }
+        result => result.map_err(RawstErr::FileError),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DownloadStatus {
+    Active,
+    Waiting,
+    Paused,
+    Error,
+    Complete,
+    Removed,
+}
+
+impl DownloadStatus {
+    /// Same names as the statuses of aria2
+    fn as_str(&self) -> &'static str {
+        match self {
+            DownloadStatus::Active => "active",
+            DownloadStatus::Waiting => "waiting",
+            DownloadStatus::Paused => "paused",
+            DownloadStatus::Error => "error",
+            DownloadStatus::Complete => "complete",
+            DownloadStatus::Removed => "removed",
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        matches!(self, DownloadStatus::Error | DownloadStatus::Complete | DownloadStatus::Removed)
+    }
+}
+
+/// A download of the daemon, its id is the id of its history record as well
+struct Download {
+    gid: String,
+    iri: IriString,
+    /// None for a download picked up from the history, which resumes from its record
+    job: Option<DownloadJob>,
+    priority: i32,
+    status: DownloadStatus,
+    error: Option<String>,
+    /// Last progress known while it wasn't running
+    progress: Option<DownloadProgress>,
+    /// The running download, or a stopped one still winding down
+    run: Option<JoinHandle<()>>,
+}
+
+impl Download {
+    fn from_record(record: &Record) -> Self {
+        Download {
+            gid: record.id.clone(),
+            iri: record.iri.clone(),
+            job: None,
+            priority: 0,
+            status: DownloadStatus::Waiting,
+            error: None,
+            progress: Some(recorded_progress(record, 0)),
+            run: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct DaemonState {
+    /// In the order they were added
+    downloads: Vec<Download>,
+    next_index: usize,
+}
+
+impl DaemonState {
+    fn find(&mut self, gid: &str) -> Result<&mut Download, RpcError> {
+        self.downloads
+            .iter_mut()
+            .find(|download| download.gid == gid)
+            .ok_or(RpcError::failed(format!("Download with id {gid:?} not found")))
+    }
+}
+
+/// Owns the engine and runs the downloads asked for through the control socket, along with the scheduled ones
+#[derive(Clone)]
+struct Daemon {
+    engine: Arc<Engine>,
+    config: Arc<Config>,
+    history_manager: Arc<HistoryManager>,
+    queue_manager: Arc<QueueManager>,
+    state: Arc<Mutex<DaemonState>>,
+    shutdown: Arc<Notify>,
+}
+
+impl Daemon {
+    fn new(config: Config) -> Self {
+        Daemon {
+            history_manager: Arc::new(HistoryManager::new(config.history_file_path.clone())),
+            queue_manager: Arc::new(QueueManager::new(config.queue_file_path())),
+            engine: Arc::new(Engine::new(config.clone())),
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(DaemonState::default())),
+            shutdown: Arc::new(Notify::new()),
+        }
+    }
+
+    async fn handle_connection(&self, stream: UnixStream) -> std::io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut shutdown = false;
+
+            let response = match serde_json::from_str::<Value>(&line) {
+                // A batch gets the responses of all its requests at once
+                Ok(Value::Array(requests)) => {
+                    let responses: Vec<Response> = requests
+                        .into_iter()
+                        .filter_map(|request| self.respond(request, &mut shutdown))
+                        .collect();
+
+                    (!responses.is_empty()).then(|| serde_json::to_string(&responses).unwrap())
+                }
+                Ok(request) => self
+                    .respond(request, &mut shutdown)
+                    .map(|response| serde_json::to_string(&response).unwrap()),
+                Err(err) => Some(serde_json::to_string(&Response::new(Value::Null, Err(RpcError::parse_error(err.to_string())))).unwrap()),
+            };
+
+            if let Some(mut response) = response {
+                response.push('\n');
+                writer.write_all(response.as_bytes()).await?;
+            }
+
+            // Only once the client got its answer
+            if shutdown {
+                self.shutdown.notify_one();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answers a single request, notifications get no response
+    fn respond(&self, request: Value, shutdown: &mut bool) -> Option<Response> {
+        let request: Request = match serde_json::from_value(request) {
+            Ok(request) => request,
+            Err(err) => return Some(Response::new(Value::Null, Err(RpcError::invalid_request(err.to_string())))),
+        };
+
+        log::debug!("Control request {} (params:{:?})", request.method, request.params);
+
+        *shutdown |= matches!(request.method.as_str(), "aria2.shutdown" | "aria2.forceShutdown");
+
+        let outcome = self.call(&request.method, request.params);
+
+        request.id.map(|id| Response::new(id, outcome))
+    }
+
+    fn call(&self, method: &str, mut params: Vec<Value>) -> Result<Value, RpcError> {
+        // aria2 clients send their secret first, the socket is only open to its user anyway
+        if params.first().and_then(Value::as_str).is_some_and(|param| param.starts_with("token:")) {
+            params.remove(0);
+        }
+
+        let ok = || Value::from("OK");
+
+        match method {
+            "aria2.addUri" => self.add_uri(&params),
+            "aria2.pause" | "aria2.forcePause" => {
+                let gid: String = required_param(&params, 0, "gid")?;
+                self.pause(&gid)?;
+
+                Ok(Value::from(gid))
+            }
+            "aria2.pauseAll" | "aria2.forcePauseAll" => {
+                self.pause_all();
+
+                Ok(ok())
+            }
+            "aria2.unpause" => {
+                let gid: String = required_param(&params, 0, "gid")?;
+                self.unpause(&gid)?;
+
+                Ok(Value::from(gid))
+            }
+            "aria2.unpauseAll" => {
+                self.unpause_all();
+
+                Ok(ok())
+            }
+            "aria2.remove" | "aria2.forceRemove" => {
+                let gid: String = required_param(&params, 0, "gid")?;
+                self.remove(&gid)?;
+
+                Ok(Value::from(gid))
+            }
+            "aria2.removeDownloadResult" => {
+                let gid: String = required_param(&params, 0, "gid")?;
+                self.remove_result(&gid)?;
+
+                Ok(ok())
+            }
+            "aria2.purgeDownloadResult" => {
+                self.state.lock().unwrap().downloads.retain(|download| !download.status.is_stopped());
+
+                Ok(ok())
+            }
+            "aria2.tellStatus" => {
+                let gid: String = required_param(&params, 0, "gid")?;
+                let keys: Option<Vec<String>> = param(&params, 1, "keys")?;
+
+                let mut state = self.state.lock().unwrap();
+                let download = state.find(&gid)?;
+
+                Ok(select_keys(self.status(download), &keys))
+            }
+            "aria2.tellActive" => {
+                let keys = param(&params, 0, "keys")?;
+
+                Ok(self.tell(|status| status == DownloadStatus::Active, 0, usize::MAX, &keys))
+            }
+            "aria2.tellWaiting" => {
+                let offset = required_param(&params, 0, "offset")?;
+                let num = required_param(&params, 1, "num")?;
+                let keys = param(&params, 2, "keys")?;
+
+                Ok(self.tell(|status| matches!(status, DownloadStatus::Waiting | DownloadStatus::Paused), offset, num, &keys))
+            }
+            "aria2.tellStopped" => {
+                let offset = required_param(&params, 0, "offset")?;
+                let num = required_param(&params, 1, "num")?;
+                let keys = param(&params, 2, "keys")?;
+
+                Ok(self.tell(|status| status.is_stopped(), offset, num, &keys))
+            }
+            "aria2.getGlobalStat" => Ok(self.global_stat()),
+            "aria2.getVersion" => Ok(json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "enabledFeatures": ["HTTPS", "Message Digest"],
+            })),
+            // The connection stops the daemon once the response is sent
+            "aria2.shutdown" | "aria2.forceShutdown" => Ok(ok()),
+            "system.listMethods" => Ok(json!(METHODS)),
+            _ => Err(RpcError::method_not_found(method)),
+        }
+    }
+
+    /// Adds a download from its URI and the same options as a links file line, headers being given as `header`
+    fn add_uri(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let uris: Vec<String> = required_param(params, 0, "uris")?;
+        let options: Map<String, Value> = param(params, 1, "options")?.unwrap_or_default();
+
+        // aria2 takes several mirrors of the same file, rawst downloads from one
+        let [uri] = uris.as_slice() else {
+            return Err(RpcError::invalid_params("expected a single URI per download"));
+        };
+
+        let iri = IriString::try_from(uri.as_str())
+            .map_err(|err| RpcError::invalid_params(format!("invalid URI {uri:?}: {err}")))?;
+
+        let job = job_from_options(iri, options, self.config.threads).map_err(|err| RpcError::invalid_params(err.to_string()))?;
+
+        Ok(Value::from(self.add(job)))
+    }
+
+    /// Adds a download, which starts as soon as there is room for it
+    fn add(&self, job: DownloadJob) -> String {
+        let mut state = self.state.lock().unwrap();
+
+        // Same scheme as the ids of the history, the download keeps it as the id of its record
+        let gid = BASE64_STANDARD.encode(Local::now().timestamp_millis().to_be_bytes()) + &state.next_index.to_string();
+        state.next_index += 1;
+
+        log::info!("Adding {} (id: {gid})", job.iri);
+
+        state.downloads.push(Download {
+            gid: gid.clone(),
+            iri: job.iri.clone(),
+            priority: job.priority,
+            job: Some(job),
+            status: DownloadStatus::Waiting,
+            error: None,
+            progress: None,
+            run: None,
+        });
+
+        self.dispatch(&mut state);
+
+        gid
+    }
+
+    /// Starts the waiting downloads of the highest priority while fewer than `max_downloads` are active
+    ///
+    /// The scheduler of the engine shares the connections between them from there
+    fn dispatch(&self, state: &mut DaemonState) {
+        let active = state
+            .downloads
+            .iter()
+            .filter(|download| download.status == DownloadStatus::Active)
+            .count();
+        let free = self.engine.max_downloads().saturating_sub(active);
+
+        let mut waiting: Vec<&mut Download> = state
+            .downloads
+            .iter_mut()
+            .filter(|download| download.status == DownloadStatus::Waiting)
+            .collect();
+
+        // Earlier downloads go first among equals
+        waiting.sort_by_key(|download| Reverse(download.priority));
+
+        for download in waiting.into_iter().take(free) {
+            self.start(download);
+        }
+    }
+
+    fn start(&self, download: &mut Download) {
+        download.status = DownloadStatus::Active;
+        download.error = None;
+
+        let previous = download.run.take();
+        let (daemon, gid, job) = (self.clone(), download.gid.clone(), download.job.clone());
+
+        download.run = Some(tokio::spawn(async move {
+            // A paused run only stops at its next await, its files are left alone until then
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+
+            let result = daemon.engine.process_tracked_job(gid.clone(), job).await;
+
+            daemon.finish(&gid, result);
+        }));
+    }
+
+    fn finish(&self, gid: &str, result: Result<(), RawstErr>) {
+        // The history knows where the file went, and its size once complete
+        let record = self.history_manager.get_record(&gid.to_string()).ok().flatten();
+
+        let mut state = self.state.lock().unwrap();
+
+        // A download paused meanwhile stays paused
+        if let Some(download) = state
+            .downloads
+            .iter_mut()
+            .find(|download| download.gid == gid && download.status == DownloadStatus::Active)
+        {
+            download.run = None;
+
+            match result {
+                Ok(()) => download.status = DownloadStatus::Complete,
+                Err(err) => {
+                    log::error!("Download of {} failed: {err}", download.iri);
+
+                    download.status = DownloadStatus::Error;
+                    download.error = Some(err.to_string());
+                }
+            }
+
+            if let Some(record) = record {
+                let completed_length = match download.status {
+                    DownloadStatus::Complete => record.file_size,
+                    _ => download.progress.as_ref().map_or(0, |progress| progress.completed_length),
+                };
+
+                download.progress = Some(recorded_progress(&record, completed_length));
+            }
+        }
+
+        self.dispatch(&mut state);
+    }
+
+    /// Stops a running download where it is, it resumes from its cache files
+    fn halt(&self, download: &mut Download) {
+        if download.status != DownloadStatus::Active {
+            return;
+        }
+
+        if let Some(progress) = self.engine.progress(&download.gid) {
+            download.progress = Some(at_rest(progress));
+        }
+
+        if let Some(run) = &download.run {
+            run.abort();
+        }
+    }
+
+    fn pause(&self, gid: &str) -> Result<(), RpcError> {
+        let mut state = self.state.lock().unwrap();
+        let download = state.find(gid)?;
+
+        match download.status {
+            DownloadStatus::Active | DownloadStatus::Waiting | DownloadStatus::Paused => {
+                self.halt(download);
+                download.status = DownloadStatus::Paused;
+            }
+            status => return Err(RpcError::failed(format!("Download {gid} can't be paused, it is {}", status.as_str()))),
+        }
+
+        self.dispatch(&mut state);
+
+        Ok(())
+    }
+
+    fn pause_all(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        for download in state.downloads.iter_mut() {
+            if matches!(download.status, DownloadStatus::Active | DownloadStatus::Waiting) {
+                self.halt(download);
+                download.status = DownloadStatus::Paused;
+            }
+        }
+    }
+
+    /// Lets a download start again, downloads of the history which aren't complete can be picked up as well
+    fn unpause(&self, gid: &str) -> Result<(), RpcError> {
+        let record = self
+            .history_manager
+            .get_record(&gid.to_string())
+            .map_err(|err| RpcError::failed(err.to_string()))?;
+
+        let mut state = self.state.lock().unwrap();
+
+        match state.find(gid) {
+            Ok(download) => match download.status {
+                // On its way already
+                DownloadStatus::Active | DownloadStatus::Waiting => return Ok(()),
+                DownloadStatus::Complete => return Err(RpcError::failed(format!("Download {gid} is complete already"))),
+                DownloadStatus::Paused | DownloadStatus::Error | DownloadStatus::Removed => download.status = DownloadStatus::Waiting,
+            },
+            Err(not_found) => match record {
+                Some(record) if record.status == "Completed" => {
+                    return Err(RpcError::failed(format!("Download {gid} is complete already")))
+                }
+                Some(record) => state.downloads.push(Download::from_record(&record)),
+                None => return Err(not_found),
+            },
+        }
+
+        self.dispatch(&mut state);
+
+        Ok(())
+    }
+
+    fn unpause_all(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        for download in state.downloads.iter_mut() {
+            if download.status == DownloadStatus::Paused {
+                download.status = DownloadStatus::Waiting;
+            }
+        }
+
+        self.dispatch(&mut state);
+    }
+
+    /// Stops a download for good, its history record and cache files are kept
+    fn remove(&self, gid: &str) -> Result<(), RpcError> {
+        let mut state = self.state.lock().unwrap();
+        let download = state.find(gid)?;
+
+        if download.status.is_stopped() {
+            return Err(RpcError::failed(format!("Download {gid} can't be removed, it is {}", download.status.as_str())));
+        }
+
+        self.halt(download);
+        download.status = DownloadStatus::Removed;
+
+        self.dispatch(&mut state);
+
+        Ok(())
+    }
+
+    fn remove_result(&self, gid: &str) -> Result<(), RpcError> {
+        let mut state = self.state.lock().unwrap();
+        let download = state.find(gid)?;
+
+        if !download.status.is_stopped() {
+            return Err(RpcError::failed(format!("Download {gid} is {}, only stopped downloads can be removed from the list", download.status.as_str())));
+        }
+
+        state.downloads.retain(|download| download.gid != gid);
+
+        Ok(())
+    }
+
+    /// Status of a download in the structure of aria2, lengths and speeds are strings
+    fn status(&self, download: &Download) -> Value {
+        let progress = match download.status {
+            DownloadStatus::Active => self.engine.progress(&download.gid),
+            _ => None,
+        };
+        let progress = progress.or_else(|| download.progress.clone().map(at_rest));
+
+        let (path, total_length, completed_length, download_speed, connections) = match &progress {
+            Some(progress) => (
+                progress.file_path.display().to_string(),
+                progress.total_length.unwrap_or(0),
+                progress.completed_length,
+                progress.download_speed,
+                progress.connections,
+            ),
+            None => (String::new(), 0, 0, 0, 0),
+        };
+        let dir = progress
+            .as_ref()
+            .and_then(|progress| progress.file_path.parent().map(|dir| dir.display().to_string()))
+            .unwrap_or_else(|| self.config.download_dir.display().to_string());
+
+        let mut status = json!({
+            "gid": download.gid,
+            "status": download.status.as_str(),
+            "totalLength": total_length.to_string(),
+            "completedLength": completed_length.to_string(),
+            "downloadSpeed": download_speed.to_string(),
+            "uploadSpeed": "0",
+            "connections": connections.to_string(),
+            "dir": dir,
+            "files": [{
+                "index": "1",
+                "path": path,
+                "length": total_length.to_string(),
+                "completedLength": completed_length.to_string(),
+                "selected": "true",
+                "uris": [{ "uri": download.iri.to_string(), "status": "used" }],
+            }],
+        });
+
+        if let Some(error) = &download.error {
+            status["errorCode"] = Value::from("1");
+            status["errorMessage"] = Value::from(error.as_str());
+        }
+
+        status
+    }
+
+    fn tell(&self, wanted: impl Fn(DownloadStatus) -> bool, offset: usize, num: usize, keys: &Option<Vec<String>>) -> Value {
+        let state = self.state.lock().unwrap();
+
+        state
+            .downloads
+            .iter()
+            .filter(|download| wanted(download.status))
+            .skip(offset)
+            .take(num)
+            .map(|download| select_keys(self.status(download), keys))
+            .collect()
+    }
+
+    fn global_stat(&self) -> Value {
+        let state = self.state.lock().unwrap();
+
+        let count = |wanted: fn(DownloadStatus) -> bool| {
+            state.downloads.iter().filter(|download| wanted(download.status)).count().to_string()
+        };
+
+        let download_speed: u64 = state
+            .downloads
+            .iter()
+            .filter(|download| download.status == DownloadStatus::Active)
+            .filter_map(|download| self.engine.progress(&download.gid))
+            .map(|progress| progress.download_speed)
+            .sum();
+
+        json!({
+            "downloadSpeed": download_speed.to_string(),
+            "uploadSpeed": "0",
+            "numActive": count(|status| status == DownloadStatus::Active),
+            "numWaiting": count(|status| matches!(status, DownloadStatus::Waiting | DownloadStatus::Paused)),
+            "numStopped": count(|status| status.is_stopped()),
+            "numStoppedTotal": count(|status| status.is_stopped()),
+        })
+    }
+
+    /// Adds the scheduled downloads as they become due, the same way as `queue run --wait` but without an end
+    async fn run_queue(self) {
+        loop {
+            match self.queue_manager.take_due(&Local::now()) {
+                Ok(due) => {
+                    for queued in due {
+                        let iri = queued.job.iri.clone();
+                        let gid = self.add(queued.job);
+
+                        println!("Started scheduled download {iri} (id: {gid})");
+                    }
+                }
+                Err(err) => log::error!("Couldn't read the queue: {err}"),
+            }
+
+            let until_next = self
+                .queue_manager
+                .time_until_next(&Local::now())
+                .ok()
+                .flatten()
+                .unwrap_or(QUEUE_POLL_INTERVAL);
+
+            tokio::time::sleep(until_next.min(QUEUE_POLL_INTERVAL)).await;
+        }
+    }
+
+    /// Stops the running downloads, which can be resumed later
+    ///
+    /// Downloads which didn't start yet have nothing to resume from, they go back to the queue instead
+    async fn stop(&self) {
+        let runs: Vec<JoinHandle<()>> = {
+            let mut state = self.state.lock().unwrap();
+
+            for download in state.downloads.iter_mut() {
+                self.halt(download);
+            }
+
+            state.downloads.iter_mut().filter_map(|download| download.run.take()).collect()
+        };
+
+        for run in runs {
+            let _ = run.await;
+        }
+
+        let unfinished: Vec<(String, Option<DownloadJob>, bool)> = self
+            .state
+            .lock()
+            .unwrap()
+            .downloads
+            .iter()
+            .filter(|download| !download.status.is_stopped())
+            .map(|download| (download.gid.clone(), download.job.clone(), download.status == DownloadStatus::Paused))
+            .collect();
+
+        let (started, unstarted): (Vec<_>, Vec<_>) = unfinished
+            .into_iter()
+            .partition(|(gid, _, _)| self.history_manager.get_record(gid).ok().flatten().is_some());
+
+        if !unstarted.is_empty() {
+            let (jobs, paused): (Vec<DownloadJob>, Vec<bool>) = unstarted
+                .into_iter()
+                .filter_map(|(_, job, paused)| job.map(|job| (job, paused)))
+                .unzip();
+
+            let requeued = self.queue_manager.add_jobs(jobs, Local::now(), None).and_then(|queued| {
+                // Paused ones stay paused there
+                for (queued, _) in queued.iter().zip(paused).filter(|(_, paused)| *paused) {
+                    self.queue_manager.set_paused(&queued.id, true)?;
+                }
+
+                Ok(queued.len())
+            });
+
+            match requeued {
+                Ok(requeued) => println!("{requeued} downloads which didn't start went back to the queue"),
+                Err(err) => log::error!("Couldn't queue the downloads which didn't start: {err}"),
+            }
+        }
+
+        if !started.is_empty() {
+            println!("{} unfinished downloads can be resumed with `rawst resume`", started.len());
+        }
+    }
+}
+
+/// The parameter at `index`, none when it wasn't given
+fn param<T: DeserializeOwned>(params: &[Value], index: usize, name: &str) -> Result<Option<T>, RpcError> {
+    params
+        .get(index)
+        .map(|value| serde_json::from_value(value.clone()).map_err(|err| RpcError::invalid_params(format!("invalid {name}: {err}"))))
+        .transpose()
+}
+
+fn required_param<T: DeserializeOwned>(params: &[Value], index: usize, name: &str) -> Result<T, RpcError> {
+    param(params, index, name)?.ok_or(RpcError::invalid_params(format!("missing {name}")))
+}
+
+/// Only keeps the keys asked for, all of them when none are given
+fn select_keys(status: Value, keys: &Option<Vec<String>>) -> Value {
+    match (status, keys) {
+        (Value::Object(fields), Some(keys)) if !keys.is_empty() => {
+            Value::Object(fields.into_iter().filter(|(key, _)| keys.contains(key)).collect())
+        }
+        (status, _) => status,
+    }
+}
+
+/// Builds the job of `aria2.addUri` from the options of a links file line, `header` being a list of `Name: value`
+fn job_from_options(iri: IriString, options: Map<String, Value>, threads: usize) -> Result<DownloadJob, RawstErr> {
+    let mut headers = HashMap::new();
+    let mut link_options = LinkOptions::new();
+
+    for (key, value) in options {
+        if key == "header" {
+            let lines = match value {
+                Value::Array(lines) => lines,
+                line => vec![line],
+            };
+
+            for line in lines {
+                let (name, value) = line
+                    .as_str()
+                    .and_then(|line| line.split_once(':'))
+                    .ok_or(RawstErr::InvalidLink(format!("invalid header {line}")))?;
+
+                headers.insert(name.trim().to_string(), value.trim().to_string());
+            }
+
+            continue;
+        }
+
+        let value = match value {
+            Value::String(value) => value,
+            Value::Number(value) => value.to_string(),
+            value => return Err(RawstErr::InvalidLink(format!("invalid value {value} for option '{key}'"))),
+        };
+
+        link_options.insert(key, value);
+    }
+
+    DownloadJob::from_link_options(iri, link_options, &headers, threads, 0)
+}
+
+fn recorded_progress(record: &Record, completed_length: u64) -> DownloadProgress {
+    DownloadProgress {
+        file_path: record.file_location.join(&record.file_name),
+        total_length: Some(record.file_size).filter(|file_size| *file_size > 0),
+        completed_length,
+        download_speed: 0,
+        connections: 0,
+    }
+}
+
+/// Progress of a download which isn't running anymore
+fn at_rest(progress: DownloadProgress) -> DownloadProgress {
+    DownloadProgress {
+        download_speed: 0,
+        connections: 0,
+        ..progress
+    }
+}
@@ -1,76 +1,208 @@
 use std::path::PathBuf;
+use std::cmp::Reverse;
 use std::collections::HashMap;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::str::FromStr;
 
-use chrono::DateTime;
+use chrono::{DateTime, Local};
 use futures::stream::{self, StreamExt};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use iri_string::types::IriString;
 use base64::{prelude::BASE64_STANDARD, Engine as Base64Engine};
+use iri_string::types::IriString;
+use reqwest::header::ACCEPT_RANGES;
 
-use crate::core::config::Config;
+use crate::core::checksum::{checksum_from_digest_headers, checksum_from_sums_file, checksum_sidecar_names, verify_checksum, Checksum};
+use crate::core::config::{Config, RemoteChangePolicy};
 use crate::core::errors::RawstErr;
 use crate::core::http_handler::HttpHandler;
-use crate::core::task::HttpTask;
-use crate::core::utils::{extract_filename_from_header, extract_filename_from_url, headers_from_file};
-use crate::core::history::HistoryManager;
+use crate::core::queue::{CronSchedule, QueueManager};
+use crate::core::rate_limit::RateLimiter;
+use crate::core::scheduler::{ConnectionLease, Scheduler};
+use crate::core::task::{DownloadJob, HttpTask};
+use crate::core::utils::{extract_filename_from_header, extract_filename_from_url, headers_from_file, parse_link_line, resolve_output_path, sibling_iri};
+use crate::core::history::{HistoryManager, Record};
 use crate::cli::args::InputSource;
 use crate::cli::args::DownloadArgs;
 use crate::cli::args::ResumeArgs;
-use crate::core::io::{get_cache_sizes, read_links};
+use crate::cli::args::{QueueArgs, QueueCommand};
+use crate::core::io::{cache_files_exist, discard_progress, read_links, restore_progress};
 
 pub async fn download(args: DownloadArgs, mut config: Config) -> Result<(), RawstErr> {
-    // TODO: Fuse url_download and list_download
-    // TODO: Support downloading many elements from each source
     log::trace!("Downloading files ({args:?}, {config:?})");
     // override the default count in config
-    if args.threads.is_some() {
+    if let Some(threads) = args.threads {
+
+        config.threads = threads;
+
+    }
+
+    if let Some(max_downloads) = args.max_downloads {
+
+        config.max_downloads = max_downloads;
+
+    }
+
+    if let Some(max_connections) = args.max_connections {
+
+        config.max_connections = max_connections;
+
+    }
+
+    if args.auto_checksum {
+
+        config.auto_checksum = true;
+
+    }
+
+    if args.adaptive {
+
+        config.adaptive.enabled = true;
+
+    }
+
+    if let Some(storage) = args.storage {
 
-        config.threads = args.threads.unwrap().into();
+        config.storage = storage;
+
+    }
+
+    if let Some(limit_rate) = args.limit_rate {
+
+        config.limit_rate = Some(limit_rate);
+        config.rate_schedule.clear();
+
+    }
+
+    if let Some(proxy) = args.proxy {
+
+        config.proxy = Some(proxy);
+
+    }
+
+    if let Some(credentials) = args.proxy_user {
+
+        config.proxy_credentials = Some(credentials);
+
+    }
+
+    if let Some(retries) = args.retries {
+
+        config.retry.max_attempts = retries;
+
+    }
+
+    if let Some(retry_delay) = args.retry_delay {
+
+        config.retry.base_delay_ms = retry_delay;
 
     }
 
     let engine= Engine::new(config);
 
-    let additional_headers: HashMap<String, String> = if args.headers_file_path.is_some() {
+    let additional_headers: HashMap<String, String> = match args.headers_file_path {
 
-        headers_from_file(args.headers_file_path.unwrap())?
+        Some(headers_file_path) => headers_from_file(headers_file_path)?,
+        None => HashMap::new(),
 
-    } else {
+    };
 
-        HashMap::new()
+    let input = args.input.ok_or(RawstErr::InvalidArgs)?;
+    let priority = args.priority.unwrap_or(0);
+
+    let mut jobs = match input {
+
+        InputSource::File(file_path) => engine.jobs_from_links_file(&file_path, &additional_headers, priority).await?,
+        InputSource::Iris(list_of_iris) => list_of_iris
+            .into_iter()
+            .map(|iri| DownloadJob {
+                priority,
+                ..DownloadJob::new(iri, additional_headers.clone(), engine.config.threads)
+            })
+            .collect(),
 
     };
 
-    if let Some(input) = args.input {
+    // Output file paths are paired positionally with the jobs, so either none or all of them must be given
+    if !args.output_file_path.is_empty() {
 
-        match input {
+        if args.output_file_path.len() != jobs.len() {
+            return Err(RawstErr::MismatchedOutputs(jobs.len(), args.output_file_path.len()));
+        }
 
-            InputSource::File(file_path) => engine.process_list_download(file_path, additional_headers).await?,
-            InputSource::Iris(list_of_iris) => {
-                let iri: IriString = list_of_iris.into_iter().next().ok_or(RawstErr::InvalidArgs)?;
-                let save_as = args.output_file_path.into_iter().next();
+        for (job, save_as) in jobs.iter_mut().zip(args.output_file_path) {
+            job.save_as = Some(save_as);
+        }
 
-                engine.process_url_download(iri, save_as, additional_headers).await?
+    }
 
-            }
+    // Checksums are paired the same way
+    if !args.checksum.is_empty() {
 
+        if args.checksum.len() != jobs.len() {
+            return Err(RawstErr::MismatchedChecksums(jobs.len(), args.checksum.len()));
         }
 
-        Ok(())
+        for (job, checksum) in jobs.iter_mut().zip(args.checksum) {
+            job.checksum = Some(checksum);
+        }
 
-    } else {
+    }
 
-        Err(RawstErr::InvalidArgs)
+    // Scheduled downloads are only queued, `rawst queue run` starts them
+    if args.at.is_some() || args.after.is_some() || args.cron.is_some() {
+
+        let start = match (args.at, args.after) {
+            (Some(at), _) => at,
+            (None, Some(after)) => Local::now() + chrono::Duration::from_std(*after).map_err(|_| RawstErr::InvalidArgs)?,
+            (None, None) => Local::now(),
+        };
+
+        return engine.schedule_batch(jobs, start, args.cron);
 
     }
 
+    engine.process_batch(jobs).await
+
 }
 
-pub async fn resume_download(args: ResumeArgs, config: Config) -> Result<(),RawstErr> {
+pub async fn resume_download(args: ResumeArgs, mut config: Config) -> Result<(),RawstErr> {
+    // A running daemon resumes the downloads itself
+    #[cfg(unix)]
+    if crate::core::daemon::resume_in_daemon(&args, &config).await? {
+        return Ok(());
+    }
+
+    if let Some(on_change) = args.on_change {
+        config.on_remote_change = on_change;
+    }
+
+    if let Some(limit_rate) = args.limit_rate {
+        config.limit_rate = Some(limit_rate);
+        config.rate_schedule.clear();
+    }
+
+    if let Some(proxy) = args.proxy {
+        config.proxy = Some(proxy);
+    }
+
+    if let Some(credentials) = args.proxy_user {
+        config.proxy_credentials = Some(credentials);
+    }
+
+    let engine= Engine::new(config);
+
+    if args.all {
+        let filter = ResumeFilter {
+            since: args.since.map(|since| *since),
+            host: args.host,
+        };
+
+        return engine.process_resume_all(&filter).await;
+    }
+
     let ids= args.download_ids;
-    let mut engine= Engine::new(config);
 
     if ids.len() > 1 {
         for id in ids {
@@ -81,86 +213,567 @@ pub async fn resume_download(args: ResumeArgs, config: Config) -> Result<(),Raws
         Ok(())
     }
     else {
-        let id= ids.iter().next().unwrap().to_string();
+        let id= ids.first().unwrap().to_string();
         engine.process_resume_request(id).await
 
     }
 
 }
 
+pub async fn queue(args: QueueArgs, config: Config) -> Result<(), RawstErr> {
+    let engine= Engine::new(config);
+
+    let (id, found, done) = match args.command {
+        QueueCommand::List => return engine.queue_manager.list_queue(),
+        QueueCommand::Run { wait } => return engine.run_queue(wait).await,
+        QueueCommand::Priority { id, priority } => {
+            let found = engine.queue_manager.set_priority(&id, priority)?;
+
+            (id, found, format!("priority set to {priority}"))
+        }
+        QueueCommand::Pause { id } => {
+            let found = engine.queue_manager.set_paused(&id, true)?;
+
+            (id, found, "paused".to_string())
+        }
+        QueueCommand::Resume { id } => {
+            let found = engine.queue_manager.set_paused(&id, false)?;
+
+            (id, found, "resumed".to_string())
+        }
+        QueueCommand::Cancel { id } => {
+            let found = engine.queue_manager.cancel_job(&id)?;
+
+            (id, found, "cancelled".to_string())
+        }
+    };
+
+    match found {
+        true => println!("Scheduled download {id} {done}"),
+        false => println!("Scheduled download with id {id:?} not found"),
+    }
+
+    Ok(())
+}
+
+/// Narrows down which unfinished downloads `resume --all` picks up
+#[derive(Debug, Default)]
+pub struct ResumeFilter {
+    /// Only downloads started within this long
+    pub since: Option<std::time::Duration>,
+    /// Only downloads from this host
+    pub host: Option<String>,
+}
+
+impl ResumeFilter {
+    pub fn matches(&self, record: &Record) -> bool {
+        if let Some(since) = self.since {
+            let started = DateTime::<Local>::from_str(&record.timestamp).ok();
+            let oldest = chrono::Duration::from_std(since).ok().map(|since| Local::now() - since);
+
+            match (started, oldest) {
+                (Some(started), Some(oldest)) if started >= oldest => (),
+                _ => return false,
+            }
+        }
+
+        if let Some(host) = &self.host {
+            let record_host = record.iri.authority_components().map(|authority| authority.host().to_string());
+
+            if !record_host.is_some_and(|record_host| record_host.eq_ignore_ascii_case(host)) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Longest sleep of `queue run --wait` between two looks at the queue
+pub const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The result of a single job of a batch
+pub struct JobOutcome {
+    pub iri: IriString,
+    pub file_name: Option<PathBuf>,
+    pub result: Result<(), RawstErr>,
+}
+
+/// A lone download reports its own error, batches get a summary instead
+fn report_outcomes(mut outcomes: Vec<JobOutcome>) -> Result<(), RawstErr> {
+    let total = outcomes.len();
+    let failed = outcomes.iter().filter(|outcome| outcome.result.is_err()).count();
+
+    if total == 1 {
+        return outcomes.pop().unwrap().result;
+    }
+
+    print_summary(&outcomes);
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(RawstErr::BatchFailed(failed, total)),
+    }
+}
+
+fn print_summary(outcomes: &[JobOutcome]) {
+    let name_width = outcomes
+        .iter()
+        .map(|outcome| outcome_name(outcome).len())
+        .max()
+        .unwrap_or(0)
+        .max("File".len());
+
+    println!("\n{:<9} | {:<name_width$} | Details", "Status", "File");
+    println!("{:-<9}-+-{:-<name_width$}-+-{:-<7}", "", "", "");
+
+    for outcome in outcomes {
+        let (status, details) = match &outcome.result {
+            Ok(()) => ("Completed", String::new()),
+            Err(err) => ("Failed", err.to_string()),
+        };
+
+        println!("{:<9} | {:<name_width$} | {}", status, outcome_name(outcome), details);
+    }
+
+    let failed = outcomes.iter().filter(|outcome| outcome.result.is_err()).count();
+    println!("\n{} completed, {} failed", outcomes.len() - failed, failed);
+}
+
+fn outcome_name(outcome: &JobOutcome) -> String {
+    match &outcome.file_name {
+        Some(file_name) => file_name.display().to_string(),
+        None => outcome.iri.to_string(),
+    }
+}
+
+/// How far a running download got
+#[derive(Clone, Debug)]
+pub struct DownloadProgress {
+    pub file_path: PathBuf,
+    /// Unknown for files streamed without a size
+    pub total_length: Option<u64>,
+    pub completed_length: u64,
+    /// Average bytes per second since the download started or resumed
+    pub download_speed: u64,
+    pub connections: usize,
+}
+
+struct ActiveDownload {
+    file_path: PathBuf,
+    total_length: Option<u64>,
+    downloaded: Arc<AtomicU64>,
+    connections: usize,
+    started: Instant,
+    // Bytes downloaded before it resumed, they don't count towards the speed
+    started_from: u64,
+}
+
+impl ActiveDownload {
+    fn progress(&self) -> DownloadProgress {
+        let completed_length = self.downloaded.load(Ordering::SeqCst);
+        let elapsed = self.started.elapsed().as_secs_f64();
+
+        // Too early to tell right after it started
+        let download_speed = match elapsed >= 1.0 {
+            true => (completed_length.saturating_sub(self.started_from) as f64 / elapsed) as u64,
+            false => 0,
+        };
+
+        DownloadProgress {
+            file_path: self.file_path.clone(),
+            total_length: self.total_length,
+            completed_length,
+            download_speed,
+            connections: self.connections,
+        }
+    }
+}
+
+/// Stops following a download once it ends, however it ends
+struct TrackedDownload<'a> {
+    active: &'a Mutex<HashMap<String, ActiveDownload>>,
+    id: String,
+}
+
+impl Drop for TrackedDownload<'_> {
+    fn drop(&mut self) {
+        self.active.lock().unwrap().remove(&self.id);
+    }
+}
+
 pub struct Engine {
     config: Config,
     http_handler: HttpHandler,
     history_manager: HistoryManager,
+    queue_manager: QueueManager,
     multi_bar: MultiProgress,
+    scheduler: Scheduler,
+    // Bandwidth shared by all downloads
+    rate_limiter: Option<Arc<RateLimiter>>,
+    // Downloads running right now by history id
+    active: Mutex<HashMap<String, ActiveDownload>>,
 }
 
 impl Engine {
     pub fn new(config: Config) -> Self {
 
         let history_manager= HistoryManager::new(config.history_file_path.clone());
+        let queue_manager= QueueManager::new(config.queue_file_path());
+        let scheduler= Scheduler::new(config.max_downloads, config.max_connections);
+        let http_handler= HttpHandler::new(&config);
+
+        let rate_limiter = match config.limit_rate.is_some() || !config.rate_schedule.is_empty() {
+            true => Some(Arc::new(RateLimiter::scheduled(config.limit_rate, config.rate_schedule.clone()))),
+            false => None,
+        };
 
         Engine {
+            scheduler,
             config,
-            http_handler: HttpHandler::new(),
+            http_handler,
             history_manager,
+            queue_manager,
             multi_bar: MultiProgress::new(),
+            rate_limiter,
+            active: Mutex::new(HashMap::new()),
         }
     }
 
-    pub async fn process_url_download(mut self, iri: IriString, save_as: Option<PathBuf>, additional_headers: HashMap<String, String>) -> Result<(), RawstErr> {
+    pub async fn process_batch(&self, jobs: Vec<DownloadJob>) -> Result<(), RawstErr> {
+        log::trace!("Processing batch of {} jobs", jobs.len());
 
-        let http_task = self.create_http_task(iri, (&save_as).into(), &additional_headers).await?;
+        if jobs.is_empty() {
+            return Err(RawstErr::InvalidArgs);
+        }
+
+        // Jobs of a higher priority start first, the order of the others is kept
+        let mut jobs = jobs;
+        jobs.sort_by_key(|job| Reverse(job.priority));
+
+        // Every job goes through the same pipeline and a failing job doesn't stop the others
+        // Only `max_downloads` jobs are polled at once, the rest start as others finish
+        let job_tasks = stream::iter(jobs.into_iter().enumerate().map(|(index, job)| async move {
+            let outcome = self.process_job(index, job, None).await;
+
+            if let Err(err) = &outcome.result {
+                log::error!("Download of {} failed: {err}", outcome.iri);
+            }
+
+            outcome
+        }));
+
+        let outcomes = job_tasks
+            .buffer_unordered(self.scheduler.max_downloads())
+            .collect::<Vec<_>>()
+            .await;
+
+        report_outcomes(outcomes)
+    }
+
+    /// Queues the jobs to start at `start`, repeating them on every occurrence of `cron` when given
+    pub fn schedule_batch(&self, jobs: Vec<DownloadJob>, start: DateTime<Local>, cron: Option<CronSchedule>) -> Result<(), RawstErr> {
+        log::trace!("Scheduling batch of {} jobs (start:{start:?}, cron:{cron:?})", jobs.len());
+
+        if jobs.is_empty() {
+            return Err(RawstErr::InvalidArgs);
+        }
+
+        // The queue is run from another working directory, relative output paths are resolved in this one
+        let jobs = jobs
+            .into_iter()
+            .map(|job| {
+                let save_as = job.save_as.as_deref().map(resolve_output_path).transpose()?;
+
+                Ok(DownloadJob { save_as, ..job })
+            })
+            .collect::<Result<Vec<_>, RawstErr>>()?;
+
+        for queued in self.queue_manager.add_jobs(jobs, start, cron)? {
+            println!("Scheduled {} for {} (id: {})", queued.job.iri, queued.next_run.format("%Y-%m-%d %H:%M"), queued.id);
+        }
 
-        let encoded_timestamp_as_id = BASE64_STANDARD.encode(http_task.timestamp.timestamp().to_be_bytes());
-    
-        self.history_manager.add_record(&http_task, &self.config, encoded_timestamp_as_id.clone())?;
-    
-        self.http_download(http_task).await?;
-    
-        self.history_manager.update_record(encoded_timestamp_as_id)?;
-    
         Ok(())
     }
 
-    pub async fn process_list_download(mut self, file_path: PathBuf, additional_headers: HashMap<String, String>) -> Result<(), RawstErr> {
-        self.config.threads = 1;
-        
-        let link_string = read_links(&file_path).await?;
-    
-        let mut tasks: HashMap<String, HttpTask> = HashMap::new();
-    
-        let url_list = link_string.split("\n").collect::<Vec<&str>>();
-        for (index, line) in url_list.iter().enumerate() {
-            let iri = line
-                .trim()
-                .parse::<IriString>()
-                .map_err(|_| RawstErr::InvalidArgs)?;
-    
-            let http_task = self.create_http_task(iri, None, &additional_headers).await?;
-    
-            // Adding index number to distinguish between each id of each task
-            let encoded_timestamp_as_id =
-                BASE64_STANDARD.encode(http_task.timestamp.timestamp().to_be_bytes()) + &index.to_string();
-    
-            self.history_manager.add_record(&http_task, &self.config, encoded_timestamp_as_id.clone())?;
+    /// Starts the queued jobs which are due
+    ///
+    /// With `wait` it keeps going until the queue is empty, starting the other jobs as they become due.
+    /// The queue is read again every `QUEUE_POLL_INTERVAL` so jobs added in the meantime are picked up
+    pub async fn run_queue(&self, wait: bool) -> Result<(), RawstErr> {
+        loop {
+            let due = self.queue_manager.take_due(&Local::now())?;
 
-            tasks.insert(encoded_timestamp_as_id, http_task);
-    
+            let result = match due.is_empty() {
+                true => {
+                    if !wait {
+                        println!("No scheduled downloads are due");
+                    }
+
+                    Ok(())
+                }
+                false => self.process_batch(due.into_iter().map(|queued| queued.job).collect()).await,
+            };
+
+            if !wait {
+                return result;
+            }
+
+            // A failing batch was already reported, the queue goes on
+            if let Err(err) = result {
+                log::error!("Scheduled downloads failed: {err}");
+            }
+
+            match self.queue_manager.time_until_next(&Local::now())? {
+                Some(until_next) => tokio::time::sleep(until_next.min(QUEUE_POLL_INTERVAL)).await,
+                None => {
+                    println!("No more scheduled downloads");
+
+                    return Ok(());
+                }
+            }
         }
+    }
 
-        let val: Vec<HttpTask> = tasks.clone().into_values().collect();
-    
-        self.list_http_download(val).await?;
-    
-        for id in tasks.keys() {
-            self.history_manager.update_record(id.to_owned())?;
+    /// Resumes every unfinished download matching `filter` through the same pipeline as batches
+    ///
+    /// Downloads whose cache files are gone start from the beginning, the same as when they are resumed on their own
+    pub async fn process_resume_all(&self, filter: &ResumeFilter) -> Result<(), RawstErr> {
+        log::trace!("Resuming all pending downloads (filter:{filter:?})");
+        let records: Vec<Record> = self
+            .history_manager
+            .get_pending()?
+            .into_iter()
+            .filter(|record| filter.matches(record))
+            .collect();
+
+        if records.is_empty() {
+            println!("No downloads to resume");
+
+            return Ok(());
         }
-    
+
+        let resume_tasks = stream::iter(records.into_iter().map(|record| async move {
+            let outcome = self.resume_pending(record, 0).await;
+
+            if let Err(err) = &outcome.result {
+                log::error!("Download of {} failed: {err}", outcome.iri);
+            }
+
+            outcome
+        }));
+
+        let outcomes = resume_tasks
+            .buffer_unordered(self.scheduler.max_downloads())
+            .collect::<Vec<_>>()
+            .await;
+
+        report_outcomes(outcomes)
+    }
+
+    /// Downloads a job, its history record is named after its start time and `index` unless `record_id` is given
+    async fn process_job(&self, index: usize, mut job: DownloadJob, record_id: Option<String>) -> JobOutcome {
+        log::trace!("Processing job (index:{index:?}, job:{job:?}, record_id:{record_id:?})");
+        let thread_limit = self.config.thread_limit(&job.iri);
+
+        if job.threads > thread_limit {
+            println!("Warning!: {} threads were asked for {} but at most {} are allowed, using {}", job.threads, job.iri, thread_limit, thread_limit);
+            job.threads = thread_limit;
+        }
+
+        let lease = self.scheduler.acquire_connections(job.threads, job.priority).await;
+        job.threads = lease.held();
+
+        let mut file_name = None;
+
+        let result = async {
+            // Adding index number to distinguish between each id of each task, the nanoseconds between each batch
+            let record_id_at = |timestamp: DateTime<Local>| {
+                record_id.clone().unwrap_or_else(|| {
+                    BASE64_STANDARD.encode(timestamp.timestamp_nanos_opt().unwrap_or_default().to_be_bytes()) + &index.to_string()
+                })
+            };
+
+            let mut http_task = match self.create_http_task(&job).await {
+                Ok(http_task) => http_task,
+                Err(err) => {
+                    self.history_manager.add_failed_record(&job, self.job_download_dir(&job), record_id_at(Local::now()), &err)?;
+
+                    return Err(err);
+                }
+            };
+            http_task.attach_lease(lease);
+            file_name = Some(http_task.filename.clone());
+
+            let encoded_timestamp_as_id = record_id_at(http_task.timestamp);
+
+            self.history_manager.add_record(&http_task, encoded_timestamp_as_id.clone())?;
+
+            self.run_task(encoded_timestamp_as_id, http_task).await
+        }.await;
+
+        JobOutcome {
+            iri: job.iri,
+            file_name,
+            result,
+        }
+    }
+
+    /// Resumes a recorded download once the scheduler gives it connections
+    async fn resume_pending(&self, record: Record, priority: i32) -> JobOutcome {
+        // The chunks of the download are fixed already, the lease only limits the connections
+        let lease = self.scheduler.acquire_connections(record.threads_used, priority).await;
+
+        let iri = record.iri.clone();
+        let file_name = Some(record.file_name.clone());
+        let result = self.resume_record(record, Some(lease)).await;
+
+        JobOutcome { iri, file_name, result }
+    }
+
+    /// Downloads a job of the daemon under the id it was given
+    ///
+    /// A job which got a history record already, or a download picked up from the history without a job,
+    /// resumes from where its cache files left off
+    pub async fn process_tracked_job(&self, id: String, job: Option<DownloadJob>) -> Result<(), RawstErr> {
+        let priority = job.as_ref().map_or(0, |job| job.priority);
+
+        match (self.history_manager.get_record(&id)?, job) {
+            (Some(record), _) if record.status == "Completed" => Ok(()),
+            (Some(record), _) => self.resume_pending(record, priority).await.result,
+            (None, Some(job)) => self.process_job(0, job, Some(id)).await.result,
+            (None, None) => Err(RawstErr::InvalidArgs),
+        }
+    }
+
+    /// Progress of the download with this history id, none unless it is running
+    pub fn progress(&self, id: &str) -> Option<DownloadProgress> {
+        self.active.lock().unwrap().get(id).map(ActiveDownload::progress)
+    }
+
+    pub fn max_downloads(&self) -> usize {
+        self.scheduler.max_downloads()
+    }
+
+    /// Follows the progress of a running task until the returned guard is dropped
+    fn track(&self, id: &str, task: &HttpTask) -> TrackedDownload<'_> {
+        self.set_active(id, task);
+
+        TrackedDownload { active: &self.active, id: id.to_string() }
+    }
+
+    fn set_active(&self, id: &str, task: &HttpTask) {
+        let active = ActiveDownload {
+            file_path: task.download_dir.join(&task.filename),
+            total_length: task.content_length(),
+            downloaded: task.total_downloaded.clone(),
+            connections: task.threads().min(task.connection_limit()),
+            started: Instant::now(),
+            started_from: task.total_downloaded.load(Ordering::SeqCst),
+        };
+
+        self.active.lock().unwrap().insert(id.to_string(), active);
+    }
+
+    /// Downloads a task which already has a history record and stores the outcome in it
+    async fn run_task(&self, id: String, mut task: HttpTask) -> Result<(), RawstErr> {
+        let result = async {
+            let _tracked = self.track(&id, &task);
+
+            if let Err(err) = self.http_download(&task).await {
+                self.start_over(&id, &mut task, err).await?;
+
+                // The file may have another size now
+                self.set_active(&id, &task);
+
+                self.http_download(&task).await?;
+            }
+
+            if let Some(expected) = &task.checksum {
+                let actual = verify_checksum(&task, expected).await?;
+
+                self.history_manager.set_checksum(id.clone(), &actual)?;
+            }
+
+            if task.content_length().is_none() {
+                self.history_manager.set_file_size(id.clone(), task.total_downloaded.load(Ordering::SeqCst))?;
+            }
+
+            Ok(())
+        }.await;
+
+        match result {
+            Ok(()) => self.history_manager.update_record(id),
+            Err(err) => {
+                self.history_manager.fail_record(id, &err)?;
+
+                Err(err)
+            }
+        }
+    }
+
+    /// Downloads the file again from the start if it changed on the server and the policy allows it,
+    /// or as a single stream if the server ignored the ranges of the chunks
+    ///
+    /// Any other error is passed through
+    async fn start_over(&self, id: &str, task: &mut HttpTask, err: RawstErr) -> Result<(), RawstErr> {
+        let ranges_ignored = matches!(err, RawstErr::RangesIgnored);
+
+        if ranges_ignored {
+            println!("Warning!: Server ignored the requested ranges, sequentially downloading..");
+        } else if err.is_remote_change() && self.config.on_remote_change == RemoteChangePolicy::Restart {
+            println!("{} changed on the server, downloading it again from the start", task.filename.display());
+        } else {
+            return Err(err);
+        }
+
+        log::warn!("Restarting {:?}: {err}", task.filename);
+
+        discard_progress(task, &self.config.cache_dir).await?;
+
+        let mut cached_headers = self.http_handler.cache_headers(&task.iri, &task.request_headers()).await?;
+
+        // Ranges aren't requested again from a server which ignores them
+        if ranges_ignored {
+            cached_headers.remove(ACCEPT_RANGES);
+        }
+
+        task.restart(cached_headers);
+
+        self.history_manager.set_restarted(id.to_string(), task)?;
+
         Ok(())
     }
 
-    pub async fn process_resume_request(&mut self, id: String) -> Result<(), RawstErr> {
+    /// Reads a links file into download jobs
+    ///
+    /// Malformed lines are reported and skipped instead of failing the whole batch
+    pub async fn jobs_from_links_file(&self, file_path: &PathBuf, additional_headers: &HashMap<String, String>, priority: i32) -> Result<Vec<DownloadJob>, RawstErr> {
+        let link_string = read_links(file_path).await?;
+
+        let mut jobs = Vec::new();
+
+        for (line_number, line) in link_string.lines().enumerate() {
+            let job = parse_link_line(line).and_then(|parsed_line| {
+                parsed_line
+                    .map(|(iri, options)| DownloadJob::from_link_options(iri, options, additional_headers, self.config.threads, priority))
+                    .transpose()
+            });
+
+            match job {
+                Ok(Some(job)) => jobs.push(job),
+                Ok(None) => (),
+                Err(err) => {
+                    log::warn!("Skipping line {} of {:?}: {err}", line_number + 1, file_path);
+                    println!("Warning!: Skipping line {} of {}: {err}", line_number + 1, file_path.display());
+                }
+            }
+        }
+
+        Ok(jobs)
+    }
+
+    pub async fn process_resume_request(&self, id: String) -> Result<(), RawstErr> {
         log::trace!("Resuming download (id:{:?}, config:{:?})", id, self.config);
         let record = if id == "auto" {
             self.history_manager.get_recent_pending()?
@@ -171,30 +784,8 @@ impl Engine {
         match record {
             Some(data) => {
                 // notice: I can also get total file size by getting content length through http_task object
-                if data.status == "Pending" {
-                    self.config.threads = data.threads_used;
-                    self.config.download_dir = data.file_location;
-
-                    let file_name = PathBuf::from(&data.file_name.file_stem().unwrap());
-
-                    let mut http_task = self
-                        .create_http_task(data.iri.clone(), Some(&file_name), &data.headers)
-                        .await?;
-
-                    http_task.timestamp = DateTime::from_str(data.timestamp.as_str()).unwrap();
-    
-                    let cache_sizes =
-                        get_cache_sizes(http_task.hashed_file_name(), data.threads_used, self.config.clone())?;
-    
-                    http_task.calculate_x_offsets(&cache_sizes);
-    
-                    http_task
-                        .total_downloaded
-                        .fetch_add(cache_sizes.iter().sum::<u64>(), Ordering::SeqCst);
-    
-                    self.http_download(http_task).await?;
-    
-                    self.history_manager.update_record(data.id)?
+                if data.status != "Completed" {
+                    self.resume_record(data, None).await?
                 } else {
                     println!("The file is already downloaded");
     
@@ -211,30 +802,82 @@ impl Engine {
         Ok(())
     }
 
-    pub async fn http_download(&self, task: HttpTask) -> Result<(), RawstErr> {
+    /// Picks an unfinished download up from where its cache files left off
+    async fn resume_record(&self, data: Record, lease: Option<Arc<ConnectionLease>>) -> Result<(), RawstErr> {
+        let mut http_task = self.resumed_http_task(&data).await?;
+
+        if let Some(lease) = lease {
+            http_task.attach_lease(lease);
+        }
+
+        // A job which failed before it started has nothing on disk, it starts from the beginning
+        if !cache_files_exist(data.hashed_file_name(), &data.file_location, &self.config.cache_dir) {
+            log::warn!("No cache files of {} ({}) were found, starting it from the beginning", data.id, data.iri);
+            println!("Warning!: No cache files of {} ({}) were found, downloading it from the beginning", data.file_name.display(), data.id);
+            self.history_manager.set_restarted(data.id.clone(), &http_task)?;
+
+            return self.run_task(data.id, http_task).await;
+        }
+
+        // Progress of another version of the file is worthless
+        let restored = match unchanged_since(&data, &http_task) {
+            Ok(()) => restore_progress(&mut http_task, &self.config.cache_dir),
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = restored {
+            if let Err(err) = self.start_over(&data.id, &mut http_task, err).await {
+                self.history_manager.fail_record(data.id, &err)?;
+
+                return Err(err);
+            }
+        }
+
+        self.run_task(data.id, http_task).await
+    }
+
+    pub async fn http_download(&self, task: &HttpTask) -> Result<(), RawstErr> {
         log::trace!("Starting HTTP download (task:{task:?})");
         let file_name_str = task.filename.display().to_string();
 
-        let progressbar = self
-            .multi_bar
-            .add(ProgressBar::new(task.content_length()).with_message(file_name_str));
+        let progressbar = match task.content_length() {
+            Some(content_length) => {
+                let progressbar = self
+                    .multi_bar
+                    .add(ProgressBar::new(content_length).with_message(file_name_str));
+
+                progressbar.set_style(ProgressStyle::with_template("{msg} | {bytes}/{total_bytes} | [{wide_bar:.green/white}] | {eta} | [{decimal_bytes_per_sec}]")
+                .unwrap()
+                .progress_chars("=>_"));
+
+                progressbar
+            }
+            // Without a size there is no bar to fill, only the received bytes and the rate are shown
+            None => {
+                let progressbar = self
+                    .multi_bar
+                    .add(ProgressBar::new_spinner().with_message(file_name_str));
+
+                progressbar.set_style(ProgressStyle::with_template("{spinner:.green} {msg} | {bytes} | {elapsed} | [{decimal_bytes_per_sec}]")
+                .unwrap());
+                progressbar.enable_steady_tick(Duration::from_millis(100));
 
-        progressbar.set_style(ProgressStyle::with_template("{msg} | {bytes}/{total_bytes} | [{wide_bar:.green/white}] | {eta} | [{decimal_bytes_per_sec}]")
-        .unwrap()
-        .progress_chars("=>_"));
+                progressbar
+            }
+        };
 
         progressbar.set_position(task.total_downloaded.load(Ordering::SeqCst));
         progressbar.reset_eta();
 
-        match self.config.threads {
+        match task.threads() {
             1 => {
                 self.http_handler
-                    .sequential_download(&task, &progressbar, &self.config)
+                    .sequential_download(task, &progressbar, &self.config)
                     .await?
             }
             _ => {
                 self.http_handler
-                    .concurrent_download(&task, &progressbar, &self.config)
+                    .concurrent_download(task, &progressbar, &self.config)
                     .await?
             }
         }
@@ -244,65 +887,167 @@ impl Engine {
         Ok(())
     }
 
-    pub async fn list_http_download(&self, tasks: Vec<HttpTask>) -> Result<(), RawstErr> {
-        let http_download_tasks = stream::iter((0..tasks.len()).map(|i| {
-            let threaded_task = tasks[i].clone();
+    /// Looks for the expected checksum of a task in its digest headers, then in sidecar files next to it
+    ///
+    /// Returns the checksum along with where it came from
+    async fn discover_checksum(&self, task: &HttpTask) -> Option<(Checksum, String)> {
+        if let Some(found) = checksum_from_digest_headers(task.headers()) {
+            return Some(found);
+        }
+
+        // Sidecars list the name of the file on the server, not the name it is saved as
+        let remote_file_name = extract_filename_from_url(&task.iri).display().to_string();
 
-            async move {
-                self.http_download(threaded_task).await?;
+        for (sidecar_name, algorithm) in checksum_sidecar_names(&remote_file_name) {
+            let Some(sidecar_iri) = sibling_iri(&task.iri, &sidecar_name) else {
+                continue;
+            };
 
-                Ok::<_, RawstErr>(())
-            }
-        }));
+            log::debug!("Looking for checksum in {sidecar_iri}");
+            let Some(content) = self.http_handler.fetch_text(&sidecar_iri, &task.request_headers()).await else {
+                continue;
+            };
 
-        http_download_tasks
-            .buffer_unordered(tasks.len())
-            .collect::<Vec<_>>()
-            .await;
+            if let Some(checksum) = checksum_from_sums_file(&content, algorithm, &remote_file_name) {
+                return Some((checksum, sidecar_iri.to_string()));
+            }
+        }
 
-        Ok(())
+        None
     }
 
-    pub async fn create_http_task(
-        &mut self,
-        iri: IriString,
-        save_as: Option<&PathBuf>,
-        additional_headers: &HashMap<String, String>
-    ) -> Result<HttpTask, RawstErr> {
-        log::trace!("Creating HTTP download task (iri:{iri:?}, save_as:{save_as:?})");
-        let cached_headers = self.http_handler.cache_headers(&iri, additional_headers).await?;
+    pub async fn create_http_task(&self, job: &DownloadJob) -> Result<HttpTask, RawstErr> {
+        log::trace!("Creating HTTP download task (job:{job:?})");
+        let mut headers = self.host_headers(&job.iri);
+        headers.extend(job.headers.clone());
+
+        let cached_headers = self.http_handler.cache_headers(&job.iri, &headers).await?;
 
         let mut filename = match extract_filename_from_header(&cached_headers) {
             Some(result) => result,
-            None => extract_filename_from_url(&iri),
+            None => extract_filename_from_url(&job.iri),
         };
 
-        if let Some(save_as) = save_as {
-            let extension = filename.extension().unwrap();
-            let output_path = save_as.parent().unwrap();
-            if output_path.exists() {
-                self.config.download_dir = output_path.to_path_buf();
+        let download_dir = self.job_download_dir(job);
 
-            }
-            
+        if let Some(save_as) = &job.save_as {
             let mut new_filename = PathBuf::from(save_as.file_name().unwrap());
-            new_filename.add_extension(extension);
+            if let Some(extension) = filename.extension() {
+                new_filename.add_extension(extension);
+            }
             filename = new_filename;
             assert!(filename.is_relative());
         }
 
-        let mut task = HttpTask::new(iri, filename, cached_headers, additional_headers.to_owned());
+        let mut task = HttpTask::new(job.iri.clone(), filename, download_dir, cached_headers, job.headers.clone());
+        task.storage = self.config.storage;
+        task.checksum = job.checksum.clone();
+        task.limit_rate = job.limit_rate;
+        self.apply_limits(&mut task);
 
-        // checks if the server allows to receive byte ranges for concurrent download
-        // otherwise uses single thread
-        if self.config.threads > 1 && !task.allows_partial_content() {
-            println!("Warning!: Server doesn't allow partial content, sequentially downloading..");
-            self.config.threads = 1
+        if task.checksum.is_none() && self.config.auto_checksum {
+            if let Some((checksum, source)) = self.discover_checksum(&task).await {
+                println!("Using checksum {} of {} from {}", checksum, task.filename.display(), source);
 
+                task.checksum = Some(checksum);
+                task.checksum_source = Some(source);
+            }
+        }
+
+        split_into_chunks(&mut task, job.threads);
+
+        Ok(task)
+    }
+
+    /// Headers of the policy of the host, read from the config each time so they are never stored
+    fn host_headers(&self, iri: &IriString) -> HashMap<String, String> {
+        self.config.host_policy(iri).map(|policy| policy.headers.clone()).unwrap_or_default()
+    }
+
+    /// Directory a job is saved to, the one of its output path if that exists
+    fn job_download_dir(&self, job: &DownloadJob) -> PathBuf {
+        match job.save_as.as_ref().and_then(|save_as| save_as.parent()) {
+            Some(output_path) if output_path.exists() => output_path.to_path_buf(),
+            _ => self.config.download_dir.clone(),
         }
+    }
 
-        task.calculate_chunks(self.config.threads as u64);
+    /// Recreates the task of a recorded download
+    ///
+    /// The exact file name and location of the record are kept, the cache files are named after them
+    async fn resumed_http_task(&self, record: &Record) -> Result<HttpTask, RawstErr> {
+        log::trace!("Recreating HTTP download task (record:{record:?})");
+        let mut headers = self.host_headers(&record.iri);
+        headers.extend(record.headers.clone());
+
+        let cached_headers = self.http_handler.cache_headers(&record.iri, &headers).await?;
+
+        let mut task = HttpTask::new(
+            record.iri.clone(),
+            record.file_name.clone(),
+            record.file_location.clone(),
+            cached_headers,
+            record.headers.clone(),
+        );
+        task.timestamp = DateTime::from_str(record.timestamp.as_str()).unwrap();
+        task.storage = record.storage;
+        task.checksum = record.expected_checksum.as_deref().and_then(|checksum| checksum.parse().ok());
+        task.checksum_source = record.checksum_source.clone();
+        task.limit_rate = record.limit_rate;
+        self.apply_limits(&mut task);
+
+        split_into_chunks(&mut task, record.threads_used);
 
         Ok(task)
     }
-}
\ No newline at end of file
+
+    /// Sets the segment size, bandwidth and host headers of a task from the config and the policy of its host
+    ///
+    /// The bandwidth is held back to the limits of all downloads, of the task itself and of its host at once
+    fn apply_limits(&self, task: &mut HttpTask) {
+        task.min_segment_size = self.config.min_segment_size(&task.iri);
+        task.host_headers = self.host_headers(&task.iri);
+
+        let host_rate = self.config.host_policy(&task.iri).and_then(|policy| policy.rate_limit);
+
+        task.rate_limiters = self.rate_limiter
+            .iter()
+            .cloned()
+            .chain([task.limit_rate, host_rate].into_iter().flatten().map(|rate| Arc::new(RateLimiter::new(rate))))
+            .collect();
+    }
+}
+
+fn split_into_chunks(task: &mut HttpTask, mut threads: usize) {
+    // checks if the server allows to receive byte ranges for concurrent download
+    // otherwise uses single thread
+    if threads > 1 && !task.allows_partial_content() {
+        println!("Warning!: Server doesn't allow partial content, sequentially downloading..");
+        threads = 1
+
+    }
+
+    // a file of unknown size can't be split, it's streamed until the server ends it
+    if threads > 1 && task.content_length().is_none() {
+        println!("Warning!: Server didn't send the file size, sequentially downloading..");
+        threads = 1
+
+    }
+
+    task.calculate_chunks(threads as u64);
+}
+
+/// Checks that the file on the server is still the one recorded when the download started
+///
+/// The `ETag` is compared when both sides have one, `Last-Modified` otherwise
+fn unchanged_since(record: &Record, task: &HttpTask) -> Result<(), RawstErr> {
+    let changed = match (&record.etag, task.etag()) {
+        (Some(recorded), Some(current)) => *recorded != current,
+        _ => matches!((&record.last_modified, task.last_modified()), (Some(recorded), Some(current)) if *recorded != current),
+    };
+
+    match changed {
+        true => Err(RawstErr::RemoteChanged),
+        false => Ok(()),
+    }
+}
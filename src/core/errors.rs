@@ -1,13 +1,18 @@
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
 use reqwest::Error as ReqwestError;
+use reqwest::StatusCode;
 
 #[derive(Debug)]
 pub enum RawstErr {
     // Startup
     InitilisationError,
     InvalidArgs,
+    MismatchedOutputs(usize, usize),
+    MismatchedChecksums(usize, usize),
+    InvalidLink(String),
     // Download
     HttpError(ReqwestError),
     Unknown(ReqwestError),
@@ -17,8 +22,23 @@ pub enum RawstErr {
     NotFound,
     InternalServerError,
     Unreachable,
+    IncompleteChunk,
+    ContentLengthChanged(u64, u64),
+    RemoteChanged,
+    RangesIgnored,
+    UnexpectedRange(String),
+    UnexpectedStatus(StatusCode),
+    BatchFailed(usize, usize),
     // Save
     FileError(io::Error),
+    IncompleteDownload(String),
+    DuplicateRecord(String),
+    ChecksumMismatch(String, String, PathBuf),
+    // Daemon
+    NoDaemon,
+    DaemonRunning(PathBuf),
+    DaemonUnsupported,
+    DaemonError(String),
 }
 
 impl fmt::Display for RawstErr {
@@ -27,6 +47,9 @@ impl fmt::Display for RawstErr {
             // Startup
             RawstErr::InitilisationError => write!(f, "Initialisation failed."),
             RawstErr::InvalidArgs => write!(f, "Invalid Arguments or No Arguments"),
+            RawstErr::MismatchedOutputs(jobs, outputs) => write!(f, "Mismatched Arguments: {} output file paths were given for {} downloads", outputs, jobs),
+            RawstErr::MismatchedChecksums(jobs, checksums) => write!(f, "Mismatched Arguments: {} checksums were given for {} downloads", checksums, jobs),
+            RawstErr::InvalidLink(reason) => write!(f, "Invalid Link: {}", reason),
             // Download
             RawstErr::HttpError(err) => write!(f, "HTTP Error: {}", err),
             RawstErr::BadRequest => write!(f, "Bad Request: The server cannot or will not process the request due to something that is perceived to be a client error."),
@@ -35,11 +58,47 @@ impl fmt::Display for RawstErr {
             RawstErr::NotFound => write!(f, "Not Found: The server has not found anything matching the Request-URI."),
             RawstErr::InternalServerError => write!(f, "Internal Server Error: The server encountered an unexpected condition which prevented it from fulfilling the request."),
             RawstErr::Unreachable => write!(f, "Unreachable: The request was not able to reach the server"),
+            RawstErr::ContentLengthChanged(before, now) => write!(f, "Remote File Changed: The file was {} bytes when the download started but is {} bytes now", before, now),
+            RawstErr::RemoteChanged => write!(f, "Remote File Changed: The file changed on the server since the download started, use `--on-change restart` to download it again"),
+            RawstErr::RangesIgnored => write!(f, "Ranges Ignored: The server sent the whole file instead of the requested range"),
+            RawstErr::UnexpectedRange(reason) => write!(f, "Unexpected Range: {}", reason),
+            RawstErr::IncompleteChunk => write!(f, "Incomplete Chunk: The server closed the connection before sending the whole chunk"),
             RawstErr::Unknown(err) => write!(f, "Unknow Error: {}", err),
+            RawstErr::UnexpectedStatus(status) => write!(f, "Unexpected Status: The server answered with {}", status),
+            RawstErr::BatchFailed(failed, total) => write!(f, "Batch Failed: {} of {} downloads failed", failed, total),
             // Save
             RawstErr::FileError(err) => write!(f, "File Error: {}", err),
+            RawstErr::ChecksumMismatch(expected, actual, corrupt_file_path) => write!(f, "Checksum Mismatch: expected {} but got {}, the file was kept as {}", expected, actual, corrupt_file_path.display()),
+            RawstErr::IncompleteDownload(reason) => write!(f, "Incomplete Download: {}, the cache files were kept to resume later", reason),
+            RawstErr::DuplicateRecord(id) => write!(f, "Duplicate Record: The history has a download with the id {} already", id),
+            // Daemon
+            RawstErr::NoDaemon => write!(f, "No Daemon: The daemon isn't running, start it with `rawst daemon`"),
+            RawstErr::DaemonRunning(socket_path) => write!(f, "Daemon Running: A daemon is listening on {} already", socket_path.display()),
+            RawstErr::DaemonUnsupported => write!(f, "Daemon Unsupported: The daemon needs Unix domain sockets, which this platform doesn't have"),
+            RawstErr::DaemonError(message) => write!(f, "Daemon Error: {}", message),
         }
     }
 }
 
+impl RawstErr {
+    /// The HTTP status code the error was created from, if any
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            RawstErr::BadRequest => Some(StatusCode::BAD_REQUEST),
+            RawstErr::Unauthorized => Some(StatusCode::UNAUTHORIZED),
+            RawstErr::Forbidden => Some(StatusCode::FORBIDDEN),
+            RawstErr::NotFound => Some(StatusCode::NOT_FOUND),
+            RawstErr::InternalServerError => Some(StatusCode::INTERNAL_SERVER_ERROR),
+            RawstErr::Unknown(err) | RawstErr::HttpError(err) => err.status(),
+            RawstErr::UnexpectedStatus(status) => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Whether the error means the file changed on the server since the download started
+    pub fn is_remote_change(&self) -> bool {
+        matches!(self, RawstErr::RemoteChanged | RawstErr::ContentLengthChanged(_, _))
+    }
+}
+
 impl std::error::Error for RawstErr {}
@@ -2,14 +2,18 @@ use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 
+use chrono::Local;
 use iri_string::types::IriString;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::cli::args::HistoryArgs;
-use crate::core::config::Config;
+use crate::core::checksum::Checksum;
+use crate::core::config::{Config, StorageMode};
 use crate::core::errors::RawstErr;
-use crate::core::task::HttpTask;
+use crate::core::rate_limit::ByteRate;
+use crate::core::task::{DownloadJob, HttpTask};
+use crate::core::utils::{extract_filename_from_url, hash_file_name};
 
 pub async fn check_history_args(args: HistoryArgs, config: Config) -> Result<(), RawstErr> {
 
@@ -43,7 +47,23 @@ pub struct Record {
     pub threads_used: usize,
     pub timestamp: String,
     pub status: String,
-    pub headers: HashMap<String, String>
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub storage: StorageMode,
+    #[serde(default)]
+    pub expected_checksum: Option<String>,
+    #[serde(default)]
+    pub checksum: Option<String>,
+    #[serde(default)]
+    pub checksum_source: Option<String>,
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+    #[serde(default)]
+    pub limit_rate: Option<ByteRate>,
 }
 
 impl Record {
@@ -68,8 +88,21 @@ impl Record {
             timestamp,
             status: "Pending".to_string(),
             headers: headers_used,
+            error: None,
+            storage: StorageMode::default(),
+            expected_checksum: None,
+            checksum: None,
+            checksum_source: None,
+            etag: None,
+            last_modified: None,
+            limit_rate: None,
         }
     }
+
+    /// Same as the hashed file name of the task the record was created from
+    pub fn hashed_file_name(&self) -> String {
+        hash_file_name(&self.iri, &self.timestamp)
+    }
 }
 
 pub struct HistoryManager {
@@ -81,7 +114,58 @@ impl HistoryManager {
         HistoryManager { file_path }
     }
 
-    pub fn add_record(&self, task: &HttpTask, config: &Config, id: String) -> Result<(), RawstErr> {
+    pub fn add_record(&self, task: &HttpTask, id: String) -> Result<(), RawstErr> {
+        let mut new_record = Record::new(
+            id,
+            task.iri.clone(),
+            task.filename.clone(),
+            task.content_length().unwrap_or(0),
+            task.download_dir.clone(),
+            task.connections,
+            task.timestamp.to_string(),
+            task.additional_headers.clone(),
+        );
+        new_record.storage = task.storage;
+        new_record.expected_checksum = task.checksum.as_ref().map(|checksum| checksum.to_string());
+        new_record.checksum_source = task.checksum_source.clone();
+        new_record.etag = task.etag();
+        new_record.last_modified = task.last_modified();
+        new_record.limit_rate = task.limit_rate;
+
+        self.push_record(new_record)
+    }
+
+    /// Records a job which failed before its download could start, eg. when the server couldn't be reached
+    ///
+    /// Nothing is known about the file yet, the record only keeps what the job asked for along with the error
+    pub fn add_failed_record(&self, job: &DownloadJob, file_location: PathBuf, id: String, err: &RawstErr) -> Result<(), RawstErr> {
+        let file_name = job
+            .save_as
+            .as_ref()
+            .and_then(|save_as| save_as.file_name())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| extract_filename_from_url(&job.iri));
+
+        let mut new_record = Record::new(
+            id,
+            job.iri.clone(),
+            file_name,
+            0,
+            file_location,
+            job.threads,
+            Local::now().to_string(),
+            job.headers.clone(),
+        );
+        new_record.status = "Failed".to_string();
+        new_record.error = Some(err.to_string());
+        new_record.expected_checksum = job.checksum.as_ref().map(|checksum| checksum.to_string());
+        new_record.limit_rate = job.limit_rate;
+
+        self.push_record(new_record)
+    }
+
+    /// Adds a record, there must be no record with the same id yet
+    fn push_record(&self, new_record: Record) -> Result<(), RawstErr> {
         // TODO: Using jsonl would mean we can simply append to the history file.
         let json_str: String = fs::read_to_string(&self.file_path).unwrap_or_else(|_| {
             panic!(
@@ -97,16 +181,9 @@ impl HistoryManager {
             )
         });
 
-        let new_record = Record::new(
-            id,
-            task.iri.clone(),
-            task.filename.clone(),
-            task.content_length(),
-            config.download_dir.clone(),
-            config.threads,
-            task.timestamp.to_string(),
-            task.additional_headers.clone(),
-        );
+        if records.iter().any(|record| record.id == new_record.id) {
+            return Err(RawstErr::DuplicateRecord(new_record.id));
+        }
 
         records.push(new_record);
 
@@ -118,6 +195,45 @@ impl HistoryManager {
     }
 
     pub fn update_record(&self, id: String) -> Result<(), RawstErr> {
+        self.modify_record(id, |record| {
+            record.status = "Completed".to_string();
+            record.error = None;
+        })
+    }
+
+    /// Stores the checksum computed from the downloaded file
+    pub fn set_checksum(&self, id: String, checksum: &Checksum) -> Result<(), RawstErr> {
+        self.modify_record(id, |record| {
+            record.checksum = Some(checksum.to_string());
+        })
+    }
+
+    /// Stores what changed when a download started over, so the next resume checks against the new file
+    pub fn set_restarted(&self, id: String, task: &HttpTask) -> Result<(), RawstErr> {
+        self.modify_record(id, |record| {
+            record.file_size = task.content_length().unwrap_or(0);
+            record.threads_used = task.connections;
+            record.etag = task.etag();
+            record.last_modified = task.last_modified();
+        })
+    }
+
+    /// Fills in the size of a download whose size was unknown until it finished
+    pub fn set_file_size(&self, id: String, file_size: u64) -> Result<(), RawstErr> {
+        self.modify_record(id, |record| {
+            record.file_size = file_size;
+        })
+    }
+
+    /// Marks the record as failed, keeping the error message for the history
+    pub fn fail_record(&self, id: String, err: &RawstErr) -> Result<(), RawstErr> {
+        self.modify_record(id, |record| {
+            record.status = "Failed".to_string();
+            record.error = Some(err.to_string());
+        })
+    }
+
+    fn modify_record(&self, id: String, modify: impl Fn(&mut Record)) -> Result<(), RawstErr> {
         let json_str = fs::read_to_string(&self.file_path).map_err(RawstErr::FileError)?;
 
         let mut records: Vec<Record> =
@@ -125,7 +241,7 @@ impl HistoryManager {
 
         for record in records.iter_mut() {
             if record.id == id {
-                record.status = "Completed".to_string();
+                modify(record);
             }
         }
 
@@ -169,18 +285,31 @@ impl HistoryManager {
         for record in result.iter() {
             println!("\nRecord\nid: {}\niri: {}\nfile name: {}\nfile size: {:?} bytes\nfile location: {}\nthreads used: {:?}\ntimestamp: {}\nstatus: {}\nheaders: {:?}",
             record.id, record.iri, record.file_name.display(), record.file_size, record.file_location.display(), record.threads_used, record.timestamp, record.status, record.headers);
+
+            if let Some(checksum) = &record.checksum {
+                println!("checksum: {}", checksum);
+            }
+
+            if let Some(checksum_source) = &record.checksum_source {
+                println!("checksum source: {}", checksum_source);
+            }
+
+            if let Some(error) = &record.error {
+                println!("error: {}", error);
+            }
         }
 
         Ok(())
     }
 
+    /// Returns the most recent download which isn't completed yet, failed downloads included
     pub fn get_recent_pending(&self) -> Result<Option<Record>, RawstErr> {
         let json_str = fs::read_to_string(&self.file_path).map_err(RawstErr::FileError)?;
 
         let records: Vec<Record> = serde_json::from_str(&json_str).expect("There are no downloads");
 
         for record in records.iter().rev() {
-            if record.status == "Pending" {
+            if record.status != "Completed" {
                 return Ok(Some(record.to_owned()));
             }
         }
@@ -188,6 +317,15 @@ impl HistoryManager {
         Ok(None)
     }
 
+    /// Every download which hasn't completed yet, oldest first
+    pub fn get_pending(&self) -> Result<Vec<Record>, RawstErr> {
+        let json_str = fs::read_to_string(&self.file_path).map_err(RawstErr::FileError)?;
+
+        let records: Vec<Record> = serde_json::from_str(&json_str).expect("There are no downloads");
+
+        Ok(records.into_iter().filter(|record| record.status != "Completed").collect())
+    }
+
     pub fn get_record(&self, id: &String) -> Result<Option<Record>, RawstErr> {
         let json_str = fs::read_to_string(&self.file_path).map_err(RawstErr::FileError)?;
 
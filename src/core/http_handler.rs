@@ -1,17 +1,27 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use futures::stream::{self, StreamExt};
+use futures::stream::{FuturesUnordered, StreamExt};
 use indicatif::ProgressBar;
 use iri_string::types::IriString;
 use reqwest::{
-    header::{HeaderMap, HeaderValue, RANGE},
-    Client, StatusCode, ClientBuilder,
+    header::{HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    Client, Response, StatusCode, ClientBuilder, Proxy,
 };
 
-use crate::core::config::Config;
+use crate::core::adaptive::ConnectionController;
+use crate::core::config::{Config, StorageMode};
 use crate::core::errors::RawstErr;
-use crate::core::io::{create_cache, create_file, merge_files};
-use crate::core::task::{ChunkType, HttpTask};
+use crate::core::proxy::ProxyResolver;
+use crate::core::io::{
+    create_cache, create_file, discard_file, finalise_file, finalise_preallocated, merge_files, preallocate_file,
+    save_control_file, verify_chunks, write_segment,
+};
+use crate::core::retry::{parse_retry_after, with_retries, AttemptError};
+use crate::core::task::{Chunk, ChunkType, HttpTask};
+
+/// Largest text file fetched by `fetch_text`
+const MAX_TEXT_SIZE: u64 = 1024 * 1024;
 
 #[derive(Clone, Default)]
 pub struct HttpHandler {
@@ -19,8 +29,13 @@ pub struct HttpHandler {
 }
 
 impl HttpHandler {
-    pub fn new() -> Self {
-        let client = ClientBuilder::new().cookie_store(true).build().unwrap();
+    pub fn new(config: &Config) -> Self {
+        let resolver = ProxyResolver::new(config);
+        let client = ClientBuilder::new()
+            .cookie_store(true)
+            .proxy(Proxy::custom(move |url| resolver.resolve(url)))
+            .build()
+            .unwrap();
 
         Self {
             client,
@@ -34,14 +49,49 @@ impl HttpHandler {
         config: &Config,
     ) -> Result<(), RawstErr> {
         log::trace!("Starting sequential download (task:{task:?}, config:{config:?})");
-        let mut headers: HeaderMap = (&task.additional_headers).try_into().expect("invalid headers");
+        save_control_file(task, &config.cache_dir)?;
 
         if let ChunkType::Single(chunk) = &task.chunk_data {
-            let range_value = format!("bytes={}-{}", chunk.x_offset, chunk.y_offset);
+            let description = format!("Download of {}", task.filename.display());
 
-            headers.insert(RANGE, HeaderValue::from_str(range_value.as_str()).unwrap());
+            with_retries(&config.retry, &description, || self.download_single(task, chunk, progressbar, config)).await?;
         }
 
+        verify_chunks(task, config).await?;
+
+        finalise_file(task, &config.cache_dir).await?;
+
+        Ok(())
+    }
+
+    async fn download_single(
+        &self,
+        task: &HttpTask,
+        chunk: &Chunk,
+        progressbar: &ProgressBar,
+        config: &Config,
+    ) -> Result<(), AttemptError> {
+        let mut headers: HeaderMap = (&task.request_headers()).try_into().expect("invalid headers");
+
+        // Continues from the last received byte when possible, otherwise starts over
+        if chunk.downloaded.load(Ordering::SeqCst) > 0 {
+            if task.allows_partial_content() {
+                // Without a content length the end of the file isn't known either
+                let range_value = match task.content_length() {
+                    Some(_) => format!("bytes={}-{}", chunk.current_offset(), chunk.y_offset()),
+                    None => format!("bytes={}-", chunk.current_offset()),
+                };
+
+                headers.insert(RANGE, HeaderValue::from_str(range_value.as_str()).unwrap());
+                insert_if_range(&mut headers, task);
+            } else {
+                discard_single(task, chunk, progressbar).await?;
+            }
+        }
+
+        let range_sent = headers.contains_key(RANGE);
+        let if_range = headers.get(IF_RANGE).cloned();
+
         let response = self
             .client
             .get(to_reqwest_url(&task.iri))
@@ -50,8 +100,27 @@ impl HttpHandler {
             .await
             .map_err(RawstErr::HttpError)?;
 
-        if response.status().is_success() {
-            create_file(task, response, progressbar, &config.download_dir).await?;
+        // Nothing is left after the last received byte
+        if range_sent && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(());
+        }
+
+        let response = check_status(response)?;
+
+        if range_sent {
+            let reply = check_range_reply(&response, chunk.current_offset(), None, task.content_length(), if_range.as_ref())?;
+
+            // The whole file is written from the start instead
+            if reply == RangeReply::WholeFile {
+                discard_single(task, chunk, progressbar).await?;
+            }
+        }
+
+        create_file(task, response, progressbar, &config.cache_dir).await?;
+
+        // Without a known size, the end of the stream is the end of the file
+        if task.content_length().is_some_and(|content_length| chunk.downloaded.load(Ordering::SeqCst) < content_length) {
+            return Err(RawstErr::IncompleteChunk.into());
         }
 
         Ok(())
@@ -64,51 +133,250 @@ impl HttpHandler {
         config: &Config,
     ) -> Result<(), RawstErr> {
         log::trace!("Starting concurrent download (task:{task:?}, config:{config:?})");
-        // Creates a stream iter for downloading each chunk separately
-        let download_tasks = stream::iter((0..config.threads).map(|i| {
-            let client = &self.client;
-            let mut headers: HeaderMap = (&task.additional_headers).try_into().expect("invalid headers");
-
-            // Creates closure for each request and IO operation
-            // Each closure has separate IO operation
-            async move {
-                if let ChunkType::Multiple(chunks) = &task.chunk_data {
-
-                    if chunks[i].is_downloaded() {
-                        log::trace!("Chunk number {i:?} skipped: {:?}", chunks[i]);
-                        return Ok(())
-                    }
+        if task.storage == StorageMode::Preallocated {
+            preallocate_file(task).await?;
+        }
+
+        save_control_file(task, &config.cache_dir)?;
+
+        match config.adaptive.enabled {
+            true => self.adaptive_workers(task, progressbar, config).await?,
+            false => self.fixed_workers(task, progressbar, config).await?,
+        }
+
+        // Refuses to merge partial data into a corrupted output file
+        verify_chunks(task, config).await?;
+
+        match task.storage {
+            StorageMode::Parts => merge_files(task, progressbar, config).await?,
+            StorageMode::Preallocated => finalise_preallocated(task, &config.cache_dir).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Runs a worker for each connection until the whole file is covered
+    async fn fixed_workers(&self, task: &HttpTask, progressbar: &ProgressBar, config: &Config) -> Result<(), RawstErr> {
+        let mut workers = (0..task.connections)
+            .map(|_| self.download_worker(task, progressbar, config, None))
+            .collect::<FuturesUnordered<_>>();
+
+        let mut first_error = None;
+
+        loop {
+            match workers.next().await {
+                Some(Ok(())) => (),
+                Some(Err(err)) => {
+                    first_error.get_or_insert(err);
+                }
+                // A chunk given up for a download of a higher priority once the others ran out of work,
+                // the lease keeps its last connection for it
+                None if first_error.is_none() && task.has_unclaimed_chunk() => {
+                    workers.push(self.download_worker(task, progressbar, config, None));
+                }
+                None => break,
+            }
+        }
+
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs workers as long as the file has bytes left, as many of them as the controller asks for
+    ///
+    /// The controller adjusts the amount on every tick, extra workers stop once their current chunk is done
+    async fn adaptive_workers(&self, task: &HttpTask, progressbar: &ProgressBar, config: &Config) -> Result<(), RawstErr> {
+        let controller = ConnectionController::new(&config.adaptive, task.connections);
+
+        let mut workers = FuturesUnordered::new();
+        let mut first_error = None;
 
-                    let range_value = format!("bytes={}-{}", chunks[i].x_offset, chunks[i].y_offset);
+        let mut interval = tokio::time::interval(controller.interval());
+        // The first tick completes right away
+        interval.tick().await;
 
-                    headers.insert(RANGE, HeaderValue::from_str(range_value.as_str()).unwrap());
+        (0..controller.target()).for_each(|_| workers.push(self.download_worker(task, progressbar, config, Some(&controller))));
 
-                    let response = client
-                        .get(to_reqwest_url(&task.iri))
-                        .headers(headers)
-                        .send()
-                        .await
-                        .map_err(RawstErr::HttpError)?;
+        loop {
+            tokio::select! {
+                result = workers.next() => match result {
+                    Some(result) => {
+                        if let Err(err) = result {
+                            first_error.get_or_insert(err);
+                        }
+                    }
+                    // A retirement raced with the end of another worker, someone has to finish the file
+                    None if first_error.is_none() && task.has_unclaimed_chunk() => {
+                        workers.push(self.download_worker(task, progressbar, config, Some(&controller)));
+                    }
+                    None => break,
+                },
+                _ = interval.tick(), if first_error.is_none() => {
+                    let target = controller.adjust(task.total_downloaded.load(Ordering::SeqCst), &task.chunks());
+                    controller.request_retirements(workers.len());
 
-                    if response.status().is_success() {
-                        create_cache(i, task, response, progressbar, &config.cache_dir).await?;
+                    // Connections taken away by a download of a higher priority aren't replaced
+                    while workers.len() < target.min(task.connection_limit()) && !controller.is_exhausted() {
+                        workers.push(self.download_worker(task, progressbar, config, Some(&controller)));
                     }
                 }
+            }
+        }
+
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps taking chunks until the whole file is covered, or the controller or the scheduler asks it to stop
+    ///
+    /// Each chunk has separate IO operation and its own retries
+    async fn download_worker(
+        &self,
+        task: &HttpTask,
+        progressbar: &ProgressBar,
+        config: &Config,
+        controller: Option<&ConnectionController>,
+    ) -> Result<(), RawstErr> {
+        let retired = AtomicBool::new(false);
+
+        while let Some((chunk_number, chunk)) = task.next_chunk() {
+            let description = format!("Chunk number {chunk_number} of {}", task.filename.display());
+
+            with_retries(&config.retry, &description, || async {
+                // A worker asked to stop between two attempts gives its chunk back to the others
+                if !chunk.is_downloaded() && (controller.is_some_and(|controller| controller.take_retirement()) || task.take_preemption()) {
+                    retired.store(true, Ordering::SeqCst);
+                    chunk.release();
+
+                    return Ok(());
+                }
+
+                let result = self.download_chunk(chunk_number, &chunk, task, progressbar, config).await;
+
+                if let (Some(controller), Err(attempt_err)) = (controller, &result) {
+                    controller.report_error(&attempt_err.err);
+                }
 
-                Ok::<_, RawstErr>(())
+                if result.is_ok() && !chunk.is_downloaded() && !chunk.in_progress() {
+                    retired.store(true, Ordering::SeqCst);
+                }
+
+                result
+            })
+            .await?;
+
+            if retired.load(Ordering::SeqCst) || controller.is_some_and(|controller| controller.take_retirement()) || task.take_preemption() {
+                return Ok(());
             }
-        }));
+        }
+
+        if let Some(controller) = controller {
+            controller.set_exhausted();
+        }
+
+        task.release_connection();
+
+        Ok(())
+    }
+
+    async fn download_chunk(
+        &self,
+        chunk_number: usize,
+        chunk: &Chunk,
+        task: &HttpTask,
+        progressbar: &ProgressBar,
+        config: &Config,
+    ) -> Result<(), AttemptError> {
+        if chunk.is_downloaded() {
+            log::trace!("Chunk number {chunk_number:?} skipped: {chunk:?}");
+            return Ok(())
+        }
+
+        let mut headers: HeaderMap = (&task.request_headers()).try_into().expect("invalid headers");
+
+        // Only requests the bytes which are still missing
+        // The end is kept as requested, splitting the chunk meanwhile moves it back
+        let y_offset = chunk.y_offset();
+        let range_value = format!("bytes={}-{}", chunk.current_offset(), y_offset);
+
+        headers.insert(RANGE, HeaderValue::from_str(range_value.as_str()).unwrap());
+        insert_if_range(&mut headers, task);
+
+        let if_range = headers.get(IF_RANGE).cloned();
+
+        let response = self
+            .client
+            .get(to_reqwest_url(&task.iri))
+            .headers(headers)
+            .send()
+            .await
+            .map_err(RawstErr::HttpError)?;
 
-        download_tasks
-            .buffer_unordered(config.threads)
-            .collect::<Vec<_>>()
-            .await;
+        // The chunk ends past the end of the file, verifying the chunks tells if anything is missing
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            log::warn!("Chunk number {chunk_number} of {:?} is not satisfiable, treating it as complete", task.filename);
+            return Ok(());
+        }
+
+        let response = check_status(response)?;
+
+        // Writing a whole file into a chunk would corrupt it
+        let reply = check_range_reply(&response, chunk.current_offset(), Some(y_offset), task.content_length(), if_range.as_ref())?;
+        if reply == RangeReply::WholeFile {
+            return Err(RawstErr::RangesIgnored.into());
+        }
 
-        merge_files(task, config).await?;
+        match task.storage {
+            StorageMode::Parts => create_cache(chunk_number, chunk, task, response, progressbar, &config.cache_dir).await?,
+            StorageMode::Preallocated => write_segment(chunk, task, response, progressbar, &config.cache_dir).await?,
+        }
+
+        // The response ended before the chunk was complete, the next attempt continues from there.
+        // A chunk released for a download of a higher priority isn't in progress anymore, another worker takes it over
+        if chunk.in_progress() {
+            return Err(RawstErr::IncompleteChunk.into());
+        }
 
         Ok(())
     }
 
+    /// Fetches a small text file such as a checksum sidecar
+    ///
+    /// Anything that isn't a successful, reasonably small response is treated as missing
+    pub async fn fetch_text(&self, iri: &IriString, additional_headers: &HashMap<String, String>) -> Option<String> {
+        let headermap: HeaderMap = (additional_headers).try_into().expect("invalid headers");
+
+        let response = self
+            .client
+            .get(to_reqwest_url(iri))
+            .headers(headermap)
+            .send()
+            .await
+            .ok()?;
+
+        if !response.status().is_success() || response.content_length().unwrap_or(0) > MAX_TEXT_SIZE {
+            return None;
+        }
+
+        // Responses without a size are only read up to the limit as well
+        let mut stream = response.bytes_stream();
+        let mut body = Vec::new();
+
+        while let Some(bytes) = stream.next().await {
+            body.extend_from_slice(&bytes.ok()?);
+
+            if body.len() as u64 > MAX_TEXT_SIZE {
+                return None;
+            }
+        }
+
+        Some(String::from_utf8_lossy(&body).into_owned())
+    }
+
     pub async fn cache_headers(&self, iri: &IriString, additional_headers: &HashMap<String, String>) -> Result<HeaderMap, RawstErr> {
 
         let headermap: HeaderMap = (additional_headers).try_into().expect("invalid headers");
@@ -124,16 +392,191 @@ impl HttpHandler {
         match response.status() {
             StatusCode::OK => Ok(response.headers().to_owned()),
 
-            StatusCode::BAD_REQUEST => Err(RawstErr::BadRequest),
-            StatusCode::UNAUTHORIZED => Err(RawstErr::Unauthorized),
-            StatusCode::FORBIDDEN => Err(RawstErr::Forbidden),
-            StatusCode::NOT_FOUND => Err(RawstErr::NotFound),
-            StatusCode::INTERNAL_SERVER_ERROR => Err(RawstErr::InternalServerError),
+            // Many CDNs and signed IRIs only allow GET
+            status => {
+                log::warn!("HEAD request for {iri} answered with {status}, probing with a ranged GET instead");
+
+                self.probe_headers(iri, additional_headers).await
+            }
+        }
+    }
+
+    /// Gets the headers of a file through a GET request for its first byte
+    ///
+    /// The total size from `Content-Range` becomes the content length, as if the headers came from HEAD
+    async fn probe_headers(&self, iri: &IriString, additional_headers: &HashMap<String, String>) -> Result<HeaderMap, RawstErr> {
+        let mut headermap: HeaderMap = (additional_headers).try_into().expect("invalid headers");
+        headermap.insert(RANGE, HeaderValue::from_static("bytes=0-0"));
+
+        let response = self
+            .client
+            .get(to_reqwest_url(iri))
+            .headers(headermap)
+            .send()
+            .await
+            .map_err(|_| RawstErr::Unreachable)?;
+
+        // The body is never read, dropping the response closes the connection
+        let mut headers = response.headers().to_owned();
+
+        let total = headers
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().strip_prefix("bytes "))
+            .and_then(|value| value.split_once('/'))
+            .and_then(|(_, total)| total.parse::<u64>().ok());
+
+        match response.status() {
+            // The server ignored the range, the headers describe the whole file already
+            StatusCode::OK => (),
+
+            // `bytes */0` for an empty file
+            StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+                headers.remove(CONTENT_LENGTH);
+                headers.remove(CONTENT_RANGE);
+                // Unlike `Repr-Digest` it is the digest of the first byte alone, not of the file
+                headers.remove("content-digest");
+
+                if let Some(total) = total {
+                    headers.insert(CONTENT_LENGTH, HeaderValue::from(total));
+                }
+
+                if response.status() == StatusCode::PARTIAL_CONTENT {
+                    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+                }
+            }
 
-            _ => Err(RawstErr::Unknown(
-                response.error_for_status().err().unwrap(),
-            )),
+            _ => return Err(status_error(response)),
         }
+
+        Ok(headers)
+    }
+}
+
+/// Passes successful responses through, otherwise keeps the `Retry-After` delay along with the error
+fn check_status(response: Response) -> Result<Response, AttemptError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = parse_retry_after(response.headers());
+
+    Err(AttemptError {
+        err: status_error(response),
+        retry_after,
+    })
+}
+
+/// Throws away what a sequential download received so far
+async fn discard_single(task: &HttpTask, chunk: &Chunk, progressbar: &ProgressBar) -> Result<(), RawstErr> {
+    discard_file(task).await?;
+
+    let discarded = chunk.downloaded.swap(0, Ordering::SeqCst);
+    task.total_downloaded.fetch_sub(discarded, Ordering::SeqCst);
+    progressbar.set_position(task.total_downloaded.load(Ordering::SeqCst));
+
+    Ok(())
+}
+
+/// Asks the server to only send the range if the file is still the one the download started with
+fn insert_if_range(headers: &mut HeaderMap, task: &HttpTask) {
+    if let Some(validator) = task.if_range().and_then(|validator| HeaderValue::from_str(&validator).ok()) {
+        headers.insert(IF_RANGE, validator);
+    }
+}
+
+/// How the server answered a request for a range
+#[derive(Debug, PartialEq)]
+enum RangeReply {
+    /// `206 Partial Content` with the requested range
+    Partial,
+    /// `200 OK` with the whole file, the range was ignored
+    WholeFile,
+}
+
+/// Checks that a request for the bytes from `start` to `end` got exactly that range back
+///
+/// A whole file sent for a range guarded by `If-Range` either means the file changed on the server
+/// or the server ignores ranges altogether, the validators of the response tell which
+fn check_range_reply(
+    response: &Response,
+    start: u64,
+    end: Option<u64>,
+    content_length: Option<u64>,
+    if_range: Option<&HeaderValue>,
+) -> Result<RangeReply, RawstErr> {
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => (),
+        StatusCode::OK => {
+            let headers = response.headers();
+
+            let unchanged = match if_range {
+                Some(validator) => [headers.get(ETAG), headers.get(LAST_MODIFIED)].contains(&Some(validator)),
+                None => true,
+            };
+
+            return match unchanged {
+                true => Ok(RangeReply::WholeFile),
+                false => Err(RawstErr::RemoteChanged),
+            };
+        }
+        status => return Err(RawstErr::UnexpectedStatus(status)),
+    }
+
+    let content_range = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    let Some((first_byte, last_byte, total)) = parse_content_range(content_range) else {
+        return Err(RawstErr::UnexpectedRange(format!("invalid Content-Range '{content_range}'")));
+    };
+
+    if let (Some(content_length), Some(total)) = (content_length, total) {
+        if total != content_length {
+            return Err(RawstErr::ContentLengthChanged(content_length, total));
+        }
+    }
+
+    // A shorter range is fine, the rest is requested on the next attempt
+    if first_byte != start || end.is_some_and(|end| last_byte > end) {
+        let requested_end = end.map(|end| end.to_string()).unwrap_or_default();
+
+        return Err(RawstErr::UnexpectedRange(format!(
+            "asked for bytes {start}-{requested_end} but got {first_byte}-{last_byte}"
+        )));
+    }
+
+    Ok(RangeReply::Partial)
+}
+
+/// Parses `bytes <first>-<last>/<total>`, the total being `*` when unknown
+fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (first_byte, last_byte) = range.split_once('-')?;
+
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+
+    Some((first_byte.parse().ok()?, last_byte.parse().ok()?, total))
+}
+
+fn status_error(response: Response) -> RawstErr {
+    match response.status() {
+        StatusCode::BAD_REQUEST => RawstErr::BadRequest,
+        StatusCode::UNAUTHORIZED => RawstErr::Unauthorized,
+        StatusCode::FORBIDDEN => RawstErr::Forbidden,
+        StatusCode::NOT_FOUND => RawstErr::NotFound,
+        StatusCode::INTERNAL_SERVER_ERROR => RawstErr::InternalServerError,
+
+        _ => match response.error_for_status() {
+            Err(err) => RawstErr::Unknown(err),
+            // Redirections and informational responses that weren't followed
+            Ok(response) => RawstErr::UnexpectedStatus(response.status()),
+        },
     }
 }
 
@@ -1,66 +1,154 @@
+use std::io::SeekFrom;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::Ordering;
 
-use futures::{future::join_all, stream::StreamExt};
+use futures::stream::StreamExt;
 use indicatif::ProgressBar;
 use reqwest::Response;
+use serde::{Deserialize, Serialize};
 use tokio::fs::{remove_file, rename, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
 
-use crate::core::config::Config;
+use crate::core::config::{Config, StorageMode};
 use crate::core::errors::RawstErr;
-use crate::core::task::{ChunkType, HttpTask};
-use crate::core::utils::chunk_file_name;
+use crate::core::task::{Chunk, ChunkType, HttpTask};
+use crate::core::utils::{chunk_file_name, control_file_name, preallocated_file_name};
 
-pub async fn merge_files(task: &HttpTask, config: &Config) -> Result<(), RawstErr> {
-    let output_path = config.download_dir.join(&task.filename);
+/// Checks that every cache file holds exactly the bytes of its chunk before anything is merged
+///
+/// The cache files are left untouched so an incomplete download can still be resumed
+pub async fn verify_chunks(task: &HttpTask, config: &Config) -> Result<(), RawstErr> {
+    let content_length = task.content_length();
+
+    if task.storage == StorageMode::Preallocated && task.threads() > 1 {
+        return verify_preallocated(task).await;
+    }
+
+    let mut total_size = 0;
+
+    for (part, chunk) in task.chunks().iter().enumerate() {
+        let chunk_path = part_file_path(task, part, &config.cache_dir);
+
+        let size = match tokio::fs::metadata(&chunk_path).await {
+            Ok(meta_data) => meta_data.len(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(RawstErr::FileError(err)),
+        };
+
+        // A sequential download is checked against the content length as a whole, if there is one
+        if task.threads() > 1 && size != chunk.size() {
+            return Err(RawstErr::IncompleteDownload(format!(
+                "chunk {} of {} has {} bytes instead of {}",
+                part, task.filename.display(), size, chunk.size()
+            )));
+        }
+
+        total_size += size;
+    }
+
+    if let Some(content_length) = content_length.filter(|content_length| total_size != *content_length) {
+        return Err(RawstErr::IncompleteDownload(format!(
+            "{} has {} bytes instead of {}",
+            task.filename.display(), total_size, content_length
+        )));
+    }
+
+    Ok(())
+}
+
+async fn verify_preallocated(task: &HttpTask) -> Result<(), RawstErr> {
+    for (part, chunk) in task.chunks().iter().enumerate() {
+        if !chunk.is_downloaded() {
+            return Err(RawstErr::IncompleteDownload(format!(
+                "segment {} of {} has {} bytes instead of {}",
+                part, task.filename.display(), chunk.downloaded.load(Ordering::SeqCst), chunk.size()
+            )));
+        }
+    }
+
+    let file_path = task.download_dir.join(preallocated_file_name(task.hashed_file_name()));
+    let size = tokio::fs::metadata(file_path).await.map_err(RawstErr::FileError)?.len();
+
+    if let Some(content_length) = task.content_length().filter(|content_length| size != *content_length) {
+        return Err(RawstErr::IncompleteDownload(format!(
+            "{} has {} bytes instead of {}",
+            task.filename.display(), size, content_length
+        )));
+    }
+
+    Ok(())
+}
+
+/// Size of the buffers used to copy the cache files into the output file
+const MERGE_BUFFER_SIZE: usize = 1024 * 1024;
+
+pub async fn merge_files(task: &HttpTask, pb: &ProgressBar, config: &Config) -> Result<(), RawstErr> {
+    let output_path = task.download_dir.join(&task.filename);
 
     let output_file = File::create(output_path)
         .await
         .map_err(RawstErr::FileError)?;
 
-    let mut output_file = BufWriter::new(output_file);
+    let mut output_file = BufWriter::with_capacity(MERGE_BUFFER_SIZE, output_file);
 
-    let mut io_tasks = Vec::new();
+    // Split chunks are appended to the list, their part files are merged in the order of the file instead
+    let mut chunks = task.chunks().into_iter().enumerate().collect::<Vec<_>>();
+    chunks.sort_by_key(|(_, chunk)| chunk.x_offset);
 
-    // Creates a closure for each temporary file read operation
-    (0..config.threads).for_each(|i| {
-        let chunk_filename = chunk_file_name(task.hashed_file_name(), i);
-        assert!(chunk_filename.is_relative());
-        let chunk_path = config.cache_dir.join(chunk_filename);
+    let chunk_paths = chunks
+        .iter()
+        .map(|(i, _)| {
+            let chunk_filename = chunk_file_name(task.hashed_file_name(), *i);
+            assert!(chunk_filename.is_relative());
 
-        let io_task = tokio::spawn(async move {
-            let temp_file = File::open(&chunk_path).await.map_err(RawstErr::FileError)?;
-            let mut temp_file = BufReader::new(temp_file);
-            let mut buffer = Vec::new();
+            config.cache_dir.join(chunk_filename)
+        })
+        .collect::<Vec<PathBuf>>();
 
-            temp_file
-                .read_to_end(&mut buffer)
-                .await
-                .map_err(RawstErr::FileError)?;
+    // Reuses the download progressbar to show the merge progress
+    let download_message = pb.message();
+    pb.set_message(format!("Merging {}", download_message));
+    pb.set_position(0);
+    pb.reset_eta();
 
-            remove_file(chunk_path).await.map_err(RawstErr::FileError)?;
+    let mut buffer = vec![0; MERGE_BUFFER_SIZE];
+    let mut merged: u64 = 0;
 
-            Ok::<_, RawstErr>(buffer)
-        });
+    // Streams each cache file in order so only one buffer is held in memory at a time
+    for chunk_path in chunk_paths.iter() {
+        let mut temp_file = File::open(chunk_path).await.map_err(RawstErr::FileError)?;
 
-        io_tasks.push(io_task);
-    });
+        loop {
+            let read = temp_file.read(&mut buffer).await.map_err(RawstErr::FileError)?;
 
-    let results = join_all(io_tasks).await;
+            if read == 0 {
+                break;
+            }
 
-    for task in results {
-        let data = task.map_err(|err| RawstErr::FileError(err.into()))??;
+            output_file
+                .write_all(&buffer[..read])
+                .await
+                .map_err(RawstErr::FileError)?;
 
-        output_file
-            .write_all(&data)
-            .await
-            .map_err(RawstErr::FileError)?;
+            merged += read as u64;
+            pb.set_position(merged);
+        }
     }
 
     output_file.flush().await.map_err(RawstErr::FileError)?;
 
+    // The cache files are only deleted once the output is safely on disk
+    output_file.get_ref().sync_all().await.map_err(RawstErr::FileError)?;
+
+    for chunk_path in chunk_paths {
+        remove_file(chunk_path).await.map_err(RawstErr::FileError)?;
+    }
+
+    remove_control_file(task, &config.cache_dir).await?;
+
+    pb.set_message(download_message);
+
     Ok(())
 }
 
@@ -68,10 +156,9 @@ pub async fn create_file(
     task: &HttpTask,
     response: Response,
     pb: &ProgressBar,
-    base_path: &Path,
+    cache_dir: &Path,
 ) -> Result<(), RawstErr> {
-    let hashed_file_name = chunk_file_name(task.hashed_file_name(), 1);
-    let file_path = base_path.join(hashed_file_name);
+    let file_path = sequential_part_path(task);
 
     let mut file = File::options()
         .append(true)
@@ -81,10 +168,20 @@ pub async fn create_file(
         .map_err(RawstErr::FileError)?;
 
     let mut stream = response.bytes_stream();
+    let mut unsaved: u64 = 0;
 
     // Recieves bytes as stream and write them into the a file
     while let Some(chunk) = stream.next().await {
-        let chunk = chunk.map_err(RawstErr::HttpError)?;
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // The next attempt appends to the file, everything counted as downloaded has to be in it by then
+                persist_single(&mut file, task).await?;
+
+                return Err(RawstErr::HttpError(err));
+            }
+        };
+        throttle(task, chunk.len() as u64).await;
 
         file.write_all(&chunk).await.map_err(RawstErr::FileError)?;
 
@@ -93,84 +190,440 @@ pub async fn create_file(
         task.total_downloaded
             .fetch_add(chunk_size, Ordering::SeqCst);
         pb.set_position(task.total_downloaded.load(Ordering::SeqCst));
+
+        // Updates downloaded bytes of the single chunk
+        if let ChunkType::Single(single_chunk) = &task.chunk_data {
+            single_chunk
+                .downloaded
+                .fetch_add(chunk_size, Ordering::SeqCst);
+        }
+
+        unsaved += chunk_size;
+        if unsaved >= CONTROL_SAVE_INTERVAL {
+            persist_single(&mut file, task).await?;
+            save_control_file(task, cache_dir)?;
+            unsaved = 0;
+        }
     }
 
-    let renamed_file_path = base_path.join(&task.filename);
+    persist_single(&mut file, task).await?;
+    save_control_file(task, cache_dir)?;
+
+    Ok(())
+}
+
+/// Renames the file of a finished sequential download to its actual name
+pub async fn finalise_file(task: &HttpTask, cache_dir: &Path) -> Result<(), RawstErr> {
+    let file_path = sequential_part_path(task);
+
+    let renamed_file_path = task.download_dir.join(&task.filename);
     rename(file_path, renamed_file_path).await.map_err(RawstErr::FileError)?;
 
+    remove_control_file(task, cache_dir).await?;
+
     Ok(())
 }
 
+/// Deletes the partially downloaded file of a sequential download so it can start over
+pub async fn discard_file(task: &HttpTask) -> Result<(), RawstErr> {
+    let file_path = sequential_part_path(task);
+
+    match remove_file(file_path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(RawstErr::FileError(err)),
+        _ => Ok(()),
+    }
+}
+
+/// Removes every cache file of a download so it can start over from nothing
+pub async fn discard_progress(task: &HttpTask, cache_dir: &Path) -> Result<(), RawstErr> {
+    let hashed_file_name = task.hashed_file_name();
+
+    let mut file_paths = vec![
+        sequential_part_path(task),
+        task.download_dir.join(preallocated_file_name(hashed_file_name.clone())),
+    ];
+    file_paths.extend((0..task.threads()).map(|part| cache_dir.join(chunk_file_name(hashed_file_name.clone(), part))));
+
+    for file_path in file_paths {
+        match remove_file(file_path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(RawstErr::FileError(err)),
+            _ => (),
+        }
+    }
+
+    remove_control_file(task, cache_dir).await
+}
+
 pub async fn create_cache(
     chunk_number: usize,
+    chunk: &Chunk,
     task: &HttpTask,
     response: Response,
     pb: &ProgressBar,
     base_path: &Path,
 ) -> Result<(), RawstErr> {
-    if let ChunkType::Multiple(chunks) = &task.chunk_data {
+    let chunk_file_name = chunk_file_name(task.hashed_file_name(),chunk_number);
+    assert!(chunk_file_name.is_relative());
+    assert!(base_path.is_dir());
 
-        let chunk_file_name = chunk_file_name(task.hashed_file_name(),chunk_number);
-        assert!(chunk_file_name.is_relative());
-        assert!(base_path.is_dir());
+    let filepath = base_path.join(chunk_file_name);
 
-        let filepath = base_path.join(chunk_file_name);
+    let mut file = File::options()
+        .append(true)
+        .create(true)
+        .open(filepath)
+        .await
+        .map_err(RawstErr::FileError)?;
 
-        let mut file = File::options()
-            .append(true)
-            .create(true)
-            .open(filepath)
-            .await
-            .map_err(RawstErr::FileError)?;
+    let mut stream = response.bytes_stream();
+    let mut unsaved: u64 = 0;
 
-        let mut stream = response.bytes_stream();
+    // Recieves bytes as stream and write them into the a file
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes.map_err(RawstErr::HttpError)?;
 
-        // Recieves bytes as stream and write them into the a file
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.map_err(RawstErr::HttpError)?;
+        // The chunk may have been split meanwhile, the rest of the response belongs to another chunk
+        let bytes = bytes.slice(..bytes.len().min(chunk.remaining() as usize));
+        throttle(task, bytes.len() as u64).await;
 
-            file.write_all(&chunk).await.map_err(RawstErr::FileError)?;
+        file.write_all(&bytes).await.map_err(RawstErr::FileError)?;
 
-            file.flush().await.map_err(RawstErr::FileError)?;
+        file.flush().await.map_err(RawstErr::FileError)?;
 
-            // Updates total download bytes and the progressbar
-            let chunk_size = chunk.len() as u64;
-            task.total_downloaded
-                .fetch_add(chunk_size, Ordering::SeqCst);
-            pb.set_position(task.total_downloaded.load(Ordering::SeqCst));
+        // Updates total download bytes and the progressbar
+        let bytes_size = bytes.len() as u64;
+        task.total_downloaded
+            .fetch_add(bytes_size, Ordering::SeqCst);
+        pb.set_position(task.total_downloaded.load(Ordering::SeqCst));
 
-            // Updates downloaded bytes for each chunk
-            chunks[chunk_number]
-                .downloaded
-                .fetch_add(chunk_size, Ordering::SeqCst);
+        // Updates downloaded bytes for each chunk
+        chunk.downloaded.fetch_add(bytes_size, Ordering::SeqCst);
+
+        unsaved += bytes_size;
+        if unsaved >= CONTROL_SAVE_INTERVAL {
+            persist(&mut file, chunk).await?;
+            save_control_file(task, base_path)?;
+            unsaved = 0;
+        }
+
+        if chunk.is_downloaded() {
+            break;
+        }
+
+        // The connection goes to a download of a higher priority, another worker carries on with the rest
+        if task.take_preemption() {
+            chunk.release();
+            break;
         }
     }
 
+    persist(&mut file, chunk).await?;
+    save_control_file(task, base_path)?;
+
     Ok(())
 }
 
-pub fn get_cache_sizes(
-    hashed_filename: String,
-    threads: usize,
-    config: Config,
-) -> Result<Vec<u64>, RawstErr> {
-    let mut cache_sizes: Vec<u64> = vec![];
+/// Holds a stream back to the bandwidth allowed for its download
+async fn throttle(task: &HttpTask, bytes: u64) {
+    for rate_limiter in &task.rate_limiters {
+        rate_limiter.consume(bytes).await;
+    }
+}
 
-    match threads > 1 {
-        false => {
-            let file_name = chunk_file_name(hashed_filename, 1);
+/// Exact state of every chunk of a download along with the content length it was started with
+///
+/// Stored in the cache directory for the whole download so resume can rebuild the chunks as they were
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ControlFile {
+    /// Unknown for files streamed without a size
+    pub content_length: Option<u64>,
+    pub segments: Vec<SegmentState>,
+}
 
-            let path = config.download_dir.join(file_name);
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SegmentState {
+    pub x_offset: u64,
+    pub y_offset: u64,
+    pub downloaded: u64,
+}
 
-            let meta_data = std::fs::metadata(path).map_err(RawstErr::FileError)?;
+/// Progress of a chunk after which the control file is saved again
+const CONTROL_SAVE_INTERVAL: u64 = 4 * 1024 * 1024;
+
+/// Creates the output file of a preallocated download with its full size
+///
+/// The file is left sparse so no actual disk space is written up front.
+/// An existing file is kept as it is so a resumed download doesn't lose its data
+pub async fn preallocate_file(task: &HttpTask) -> Result<(), RawstErr> {
+    let file_path = task.download_dir.join(preallocated_file_name(task.hashed_file_name()));
+
+    let file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&file_path)
+        .await
+        .map_err(RawstErr::FileError)?;
+
+    let size = file.metadata().await.map_err(RawstErr::FileError)?.len();
+
+    // Concurrent downloads always know the size of the file
+    let content_length = task.content_length().unwrap_or(0);
+
+    if size != content_length {
+        file.set_len(content_length).await.map_err(RawstErr::FileError)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the progress of every segment into the control file
+///
+/// Only the bytes each segment synced to disk are saved, the others may still be in flight in the file handle
+/// of their worker. Written synchronously through a temporary file so it's never left half written
+pub fn save_control_file(task: &HttpTask, cache_dir: &Path) -> Result<(), RawstErr> {
+    let segments = task
+        .chunks()
+        .iter()
+        .map(|chunk| SegmentState {
+            x_offset: chunk.x_offset,
+            y_offset: chunk.y_offset(),
+            downloaded: chunk.persisted(),
+        })
+        .collect();
+
+    let control_file = ControlFile {
+        content_length: task.content_length(),
+        segments,
+    };
+
+    let file_path = cache_dir.join(control_file_name(task.hashed_file_name()));
+    let temp_file_path = file_path.with_added_extension("tmp");
+
+    let json_str = serde_json::to_string(&control_file).unwrap();
+
+    std::fs::write(&temp_file_path, json_str).map_err(RawstErr::FileError)?;
+    std::fs::rename(temp_file_path, file_path).map_err(RawstErr::FileError)?;
+
+    Ok(())
+}
+
+pub fn read_control_file(task: &HttpTask, cache_dir: &Path) -> Result<ControlFile, RawstErr> {
+    let file_path = cache_dir.join(control_file_name(task.hashed_file_name()));
+
+    let json_str = std::fs::read_to_string(file_path).map_err(RawstErr::FileError)?;
+
+    serde_json::from_str(&json_str).map_err(|err| RawstErr::FileError(err.into()))
+}
+
+/// Streams a segment into its place of the preallocated output file
+pub async fn write_segment(
+    chunk: &Chunk,
+    task: &HttpTask,
+    response: Response,
+    pb: &ProgressBar,
+    cache_dir: &Path,
+) -> Result<(), RawstErr> {
+    let file_path = task.download_dir.join(preallocated_file_name(task.hashed_file_name()));
+
+    let mut file = File::options()
+        .write(true)
+        .open(file_path)
+        .await
+        .map_err(RawstErr::FileError)?;
+
+    // Every segment has its own file handle, so seeking doesn't affect the others
+    file.seek(SeekFrom::Start(chunk.current_offset()))
+        .await
+        .map_err(RawstErr::FileError)?;
+
+    let mut stream = response.bytes_stream();
+    let mut unsaved: u64 = 0;
+
+    // Recieves bytes as stream and write them at the offset of the segment
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes.map_err(RawstErr::HttpError)?;
+
+        // The segment may have been split meanwhile, writing past its end would overwrite the next one
+        let bytes = bytes.slice(..bytes.len().min(chunk.remaining() as usize));
+        throttle(task, bytes.len() as u64).await;
+
+        file.write_all(&bytes).await.map_err(RawstErr::FileError)?;
+
+        // Updates total download bytes and the progressbar
+        let bytes_size = bytes.len() as u64;
+        task.total_downloaded
+            .fetch_add(bytes_size, Ordering::SeqCst);
+        pb.set_position(task.total_downloaded.load(Ordering::SeqCst));
+
+        chunk.downloaded.fetch_add(bytes_size, Ordering::SeqCst);
+
+        // The data has to reach the disk before the control file claims it
+        unsaved += bytes_size;
+        if unsaved >= CONTROL_SAVE_INTERVAL {
+            persist(&mut file, chunk).await?;
+            save_control_file(task, cache_dir)?;
+            unsaved = 0;
+        }
+
+        if chunk.is_downloaded() {
+            break;
+        }
+
+        // The connection goes to a download of a higher priority, another worker carries on with the rest
+        if task.take_preemption() {
+            chunk.release();
+            break;
+        }
+    }
+
+    persist(&mut file, chunk).await?;
+    save_control_file(task, cache_dir)?;
+
+    Ok(())
+}
+
+/// Syncs the data written for a chunk to disk, after which the control file may claim it
+async fn persist(file: &mut File, chunk: &Chunk) -> Result<(), RawstErr> {
+    file.flush().await.map_err(RawstErr::FileError)?;
+    file.sync_data().await.map_err(RawstErr::FileError)?;
+
+    chunk.mark_persisted();
+
+    Ok(())
+}
+
+async fn persist_single(file: &mut File, task: &HttpTask) -> Result<(), RawstErr> {
+    match &task.chunk_data {
+        ChunkType::Single(single_chunk) => persist(file, single_chunk).await,
+        _ => file.flush().await.map_err(RawstErr::FileError),
+    }
+}
+
+/// Renames the finished preallocated file to its actual name and removes its control file
+pub async fn finalise_preallocated(task: &HttpTask, cache_dir: &Path) -> Result<(), RawstErr> {
+    let file_path = task.download_dir.join(preallocated_file_name(task.hashed_file_name()));
+
+    let file = File::open(&file_path).await.map_err(RawstErr::FileError)?;
+    file.sync_all().await.map_err(RawstErr::FileError)?;
+
+    let renamed_file_path = task.download_dir.join(&task.filename);
+    rename(file_path, renamed_file_path).await.map_err(RawstErr::FileError)?;
+
+    remove_control_file(task, cache_dir).await?;
+
+    Ok(())
+}
+
+/// Removes the control file of a finished download
+pub async fn remove_control_file(task: &HttpTask, cache_dir: &Path) -> Result<(), RawstErr> {
+    match remove_file(cache_dir.join(control_file_name(task.hashed_file_name()))).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(RawstErr::FileError(err)),
+        _ => Ok(()),
+    }
+}
+
+/// Rebuilds the chunks of a resumed download and how much of each was already downloaded
+///
+/// The chunk layout comes from the control file, part files being appended to their sizes are more recent
+/// than the saved progress and take precedence. A missing part file only means its chunk starts over.
+/// Downloads started before control files existed fall back to the sizes of their cache files
+pub fn restore_progress(task: &mut HttpTask, cache_dir: &Path) -> Result<(), RawstErr> {
+    match read_control_file(task, cache_dir) {
+        Ok(control_file) => {
+            if let (Some(saved), Some(current)) = (control_file.content_length, task.content_length()) {
+                if saved != current {
+                    return Err(RawstErr::ContentLengthChanged(saved, current));
+                }
+            }
+
+            task.restore_chunks(&control_file.segments);
+
+            if !(task.storage == StorageMode::Preallocated && task.threads() > 1) {
+                for (part, chunk) in task.chunks().iter().enumerate() {
+                    let part_path = part_file_path(task, part, cache_dir);
+
+                    let size = match std::fs::metadata(&part_path) {
+                        Ok(meta_data) => meta_data.len(),
+                        Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+                        Err(err) => return Err(RawstErr::FileError(err)),
+                    };
+
+                    // More data than the chunk can hold means the part file can't be trusted,
+                    // without a content length there is no way to tell
+                    let size = if task.content_length().is_some() && size > chunk.size() {
+                        log::warn!("Discarding {:?}, it holds {} bytes for a chunk of {}", part_path, size, chunk.size());
+                        std::fs::remove_file(&part_path).map_err(RawstErr::FileError)?;
+
+                        0
+                    } else {
+                        size
+                    };
+
+                    chunk.restore_downloaded(size);
+                }
+            }
+        }
+        Err(_) => {
+            let cache_sizes = get_cache_sizes(task, cache_dir)?;
+
+            task.set_downloaded(&cache_sizes);
+        }
+    }
+
+    let total_downloaded = task
+        .chunks()
+        .iter()
+        .map(|chunk| chunk.downloaded.load(Ordering::SeqCst))
+        .sum();
+
+    task.total_downloaded.store(total_downloaded, Ordering::SeqCst);
+
+    Ok(())
+}
+
+/// Checks if anything is left of an unfinished download to resume it from
+pub fn cache_files_exist(hashed_file_name: String, download_dir: &Path, cache_dir: &Path) -> bool {
+    [
+        cache_dir.join(control_file_name(hashed_file_name.clone())),
+        cache_dir.join(chunk_file_name(hashed_file_name.clone(), 0)),
+        download_dir.join(chunk_file_name(hashed_file_name.clone(), 1)),
+        download_dir.join(preallocated_file_name(hashed_file_name)),
+    ]
+    .iter()
+    .any(|file_path| file_path.exists())
+}
+
+/// Path of the part file of a chunk
+fn part_file_path(task: &HttpTask, part: usize, cache_dir: &Path) -> PathBuf {
+    match task.threads() > 1 {
+        true => cache_dir.join(chunk_file_name(task.hashed_file_name(), part)),
+        false => sequential_part_path(task),
+    }
+}
+
+/// Path of the part file of a sequential download
+///
+/// It lives next to the output file so finishing the download is a rename on the same file system.
+/// Resumed downloads must use the download directory of their record for it to be found again
+pub fn sequential_part_path(task: &HttpTask) -> PathBuf {
+    task.download_dir.join(chunk_file_name(task.hashed_file_name(), 1))
+}
+
+pub fn get_cache_sizes(task: &HttpTask, cache_dir: &Path) -> Result<Vec<u64>, RawstErr> {
+    let mut cache_sizes: Vec<u64> = vec![];
+
+    match task.threads() > 1 {
+        false => {
+            let meta_data = std::fs::metadata(sequential_part_path(task)).map_err(RawstErr::FileError)?;
 
             cache_sizes.push(meta_data.len());
         }
         true => {
-            (0..threads).try_for_each(|i| {
-                let chunk_filename = chunk_file_name(hashed_filename.clone(), i);
+            (0..task.threads()).try_for_each(|i| {
+                let chunk_filename = chunk_file_name(task.hashed_file_name(), i);
 
-                let path = config.cache_dir.join(chunk_filename);
+                let path = cache_dir.join(chunk_filename);
 
                 let meta_data = std::fs::metadata(path).map_err(RawstErr::FileError)?;
 
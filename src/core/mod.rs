@@ -1,9 +1,20 @@
+pub mod adaptive;
+pub mod checksum;
 pub mod config;
+#[cfg(unix)]
+pub mod daemon;
 pub mod engine;
 pub mod errors;
 pub mod history;
 pub mod http_handler;
 pub mod io;
 pub mod logger;
+pub mod proxy;
+pub mod queue;
+pub mod rate_limit;
+pub mod retry;
+#[cfg(unix)]
+pub mod rpc;
+pub mod scheduler;
 pub mod task;
 pub mod utils;
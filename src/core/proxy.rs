@@ -0,0 +1,260 @@
+use std::fmt;
+use std::str::FromStr;
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::core::config::{Config, HostPolicy};
+
+/// Where requests go through, written as `direct` or as the URL of a proxy
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ProxyTarget {
+    /// Straight to the server, even when the environment sets a proxy
+    Direct,
+    /// Through a `http://`, `https://`, `socks5://` or `socks5h://` proxy, credentials given as `user:password@`
+    Via(Url),
+}
+
+impl FromStr for ProxyTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if s.eq_ignore_ascii_case("direct") {
+            return Ok(ProxyTarget::Direct);
+        }
+
+        // `host:port` alone is a HTTP proxy, like curl does it
+        let url = match s.contains("://") {
+            true => Url::parse(s),
+            false => Url::parse(&format!("http://{s}")),
+        }
+        .map_err(|err| format!("invalid proxy '{s}': {err}"))?;
+
+        if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            return Err(format!("unsupported proxy scheme '{}', expected http, https, socks5 or socks5h", url.scheme()));
+        }
+
+        if url.host_str().is_none_or(|host| host.is_empty()) {
+            return Err(format!("invalid proxy '{s}': no host"));
+        }
+
+        Ok(ProxyTarget::Via(url))
+    }
+}
+
+impl TryFrom<String> for ProxyTarget {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for ProxyTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyTarget::Direct => write!(f, "direct"),
+            ProxyTarget::Via(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+// Keeps the password out of the logs
+impl fmt::Debug for ProxyTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyTarget::Direct => write!(f, "Direct"),
+            ProxyTarget::Via(url) => {
+                let mut url = url.clone();
+
+                if url.password().is_some() {
+                    let _ = url.set_password(Some("***"));
+                }
+
+                write!(f, "Via({})", url)
+            }
+        }
+    }
+}
+
+impl From<ProxyTarget> for String {
+    fn from(target: ProxyTarget) -> Self {
+        target.to_string()
+    }
+}
+
+/// Username and password of a proxy, written as `user:password`
+#[derive(Clone, PartialEq)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl FromStr for ProxyCredentials {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (username, password) = s.split_once(':').unwrap_or((s, ""));
+
+        if username.is_empty() {
+            return Err("expected the credentials as USER:PASSWORD".to_string());
+        }
+
+        Ok(ProxyCredentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+impl ProxyCredentials {
+    /// Sets the credentials sent to the proxy at `url`
+    fn apply(&self, mut url: Url) -> Url {
+        // Only fails for URLs without a host, which were refused when parsing
+        let _ = url.set_username(&self.username);
+        let _ = url.set_password(Some(&self.password));
+
+        url
+    }
+}
+
+impl fmt::Debug for ProxyCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:***", self.username)
+    }
+}
+
+/// Proxy environment variables, the lowercase names take precedence
+#[derive(Clone, Debug, Default)]
+pub struct ProxyEnvironment {
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub all_proxy: Option<String>,
+    pub no_proxy: Option<String>,
+}
+
+impl ProxyEnvironment {
+    pub fn from_env() -> Self {
+        ProxyEnvironment {
+            http_proxy: env_var(&["http_proxy", "HTTP_PROXY"]),
+            https_proxy: env_var(&["https_proxy", "HTTPS_PROXY"]),
+            all_proxy: env_var(&["all_proxy", "ALL_PROXY"]),
+            no_proxy: env_var(&["no_proxy", "NO_PROXY"]),
+        }
+    }
+}
+
+/// Decides which proxy each request goes through
+///
+/// The policy of the host comes first, then hosts listed in `NO_PROXY` go direct. After that the proxy
+/// of the command line or the config is used, or the `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY`
+/// environment variables when there is none
+pub struct ProxyResolver {
+    hosts: Vec<HostPolicy>,
+    proxy: Option<ProxyTarget>,
+    http_proxy: Option<Url>,
+    https_proxy: Option<Url>,
+    all_proxy: Option<Url>,
+    no_proxy: Vec<String>,
+    credentials: Option<ProxyCredentials>,
+}
+
+impl ProxyResolver {
+    pub fn new(config: &Config) -> Self {
+        Self::with_environment(config, ProxyEnvironment::from_env())
+    }
+
+    pub fn with_environment(config: &Config, environment: ProxyEnvironment) -> Self {
+        let no_proxy = environment
+            .no_proxy
+            .map(|list| {
+                list.split(',')
+                    .map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase())
+                    .filter(|entry| !entry.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        ProxyResolver {
+            hosts: config.hosts.clone(),
+            proxy: config.proxy.clone(),
+            http_proxy: env_proxy("HTTP_PROXY", environment.http_proxy),
+            https_proxy: env_proxy("HTTPS_PROXY", environment.https_proxy),
+            all_proxy: env_proxy("ALL_PROXY", environment.all_proxy),
+            no_proxy,
+            credentials: config.proxy_credentials.clone(),
+        }
+    }
+
+    /// Proxy for a request to `url`, none for a direct connection
+    ///
+    /// The credentials of the command line replace those of the proxy, wherever it comes from
+    pub fn resolve(&self, url: &Url) -> Option<Url> {
+        let proxy = self.select(url)?;
+
+        match &self.credentials {
+            Some(credentials) => Some(credentials.apply(proxy)),
+            None => Some(proxy),
+        }
+    }
+
+    fn select(&self, url: &Url) -> Option<Url> {
+        let host = url.host_str()?.to_ascii_lowercase();
+
+        if let Some(target) = self.hosts.iter().find(|policy| policy.matches(&host)).and_then(|policy| policy.proxy.as_ref()) {
+            return via(target);
+        }
+
+        if self.bypasses(&host) {
+            return None;
+        }
+
+        if let Some(target) = &self.proxy {
+            return via(target);
+        }
+
+        let scheme_proxy = match url.scheme() {
+            "https" => &self.https_proxy,
+            _ => &self.http_proxy,
+        };
+
+        scheme_proxy.as_ref().or(self.all_proxy.as_ref()).cloned()
+    }
+
+    /// Whether `NO_PROXY` lists the host, or one of the domains it belongs to
+    fn bypasses(&self, host: &str) -> bool {
+        self.no_proxy
+            .iter()
+            .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{entry}")))
+    }
+}
+
+fn via(target: &ProxyTarget) -> Option<Url> {
+    match target {
+        ProxyTarget::Direct => None,
+        ProxyTarget::Via(url) => Some(url.clone()),
+    }
+}
+
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn env_proxy(name: &str, value: Option<String>) -> Option<Url> {
+    let value = value.filter(|value| !value.trim().is_empty())?;
+
+    match value.parse::<ProxyTarget>() {
+        Ok(target) => via(&target),
+        Err(err) => {
+            log::warn!("Ignoring {name}: {err}");
+
+            None
+        }
+    }
+}
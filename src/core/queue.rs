@@ -0,0 +1,324 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use base64::{prelude::BASE64_STANDARD, Engine as Base64Engine};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use croner::Cron;
+use serde::{Deserialize, Serialize};
+
+use crate::core::errors::RawstErr;
+use crate::core::task::DownloadJob;
+
+/// Recurring schedule as a cron expression, eg. `0 2 * * *` every night at 02:00
+///
+/// Five fields (minute, hour, day of month, month, day of week) or six with the seconds first, in local time
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronSchedule {
+    expression: String,
+    cron: Box<Cron>,
+}
+
+impl CronSchedule {
+    /// First time the schedule is due after `time`
+    pub fn next_after(&self, time: &DateTime<Local>) -> Option<DateTime<Local>> {
+        self.cron.find_next_occurrence(time, false).ok()
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expression = s.trim().to_string();
+
+        let cron = Cron::new(&expression)
+            .with_seconds_optional()
+            .parse()
+            .map_err(|err| format!("invalid cron expression '{expression}': {err}"))?;
+
+        Ok(CronSchedule { expression, cron: Box::new(cron) })
+    }
+}
+
+impl PartialEq for CronSchedule {
+    fn eq(&self, other: &Self) -> bool {
+        self.expression == other.expression
+    }
+}
+
+impl TryFrom<String> for CronSchedule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl From<CronSchedule> for String {
+    fn from(schedule: CronSchedule) -> Self {
+        schedule.expression
+    }
+}
+
+/// Parses the local time given to `--at`, eg. `2026-11-01 02:00`
+pub fn parse_start_time(s: &str) -> Result<DateTime<Local>, String> {
+    let s = s.trim();
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Local));
+    }
+
+    ["%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .ok_or(format!("invalid time '{s}', expected YYYY-MM-DD HH:MM"))
+}
+
+/// A download waiting in the queue for its start time
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueuedJob {
+    pub id: String,
+    #[serde(flatten)]
+    pub job: DownloadJob,
+    pub added: DateTime<Local>,
+    /// When the job starts next
+    pub next_run: DateTime<Local>,
+    /// Starts the job again on every occurrence once it ran, otherwise it leaves the queue when it starts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cron: Option<CronSchedule>,
+    /// Stays in the queue without starting until resumed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
+}
+
+impl QueuedJob {
+    pub fn is_due(&self, now: &DateTime<Local>) -> bool {
+        !self.paused && self.next_run <= *now
+    }
+}
+
+/// Persistent list of the scheduled downloads, stored next to the history
+pub struct QueueManager {
+    pub file_path: PathBuf,
+}
+
+impl QueueManager {
+    pub fn new(file_path: PathBuf) -> Self {
+        QueueManager { file_path }
+    }
+
+    /// Every queued job, soonest first
+    pub fn get_queue(&self) -> Result<Vec<QueuedJob>, RawstErr> {
+        // The queue file is only created once something is scheduled
+        let json_str = match fs::read_to_string(&self.file_path) {
+            Ok(json_str) => json_str,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(RawstErr::FileError(err)),
+        };
+
+        let mut queue: Vec<QueuedJob> = serde_json::from_str(&json_str).unwrap_or_else(|_| {
+            panic!(
+                "Couldn't parse queue database at '{}'.",
+                self.file_path.display()
+            )
+        });
+
+        queue.sort_by_key(|queued| queued.next_run);
+
+        Ok(queue)
+    }
+
+    /// Written through a temporary file so a reader never finds it half written
+    fn save_queue(&self, queue: &[QueuedJob]) -> Result<(), RawstErr> {
+        let new_json_str = serde_json::to_string_pretty(queue).unwrap();
+        let temp_file_path = self.file_path.with_added_extension("tmp");
+
+        fs::write(&temp_file_path, new_json_str).map_err(RawstErr::FileError)?;
+        fs::rename(temp_file_path, &self.file_path).map_err(RawstErr::FileError)
+    }
+
+    /// Reads, changes and saves the queue while holding a lock on it, `update` returns none to leave it unchanged
+    ///
+    /// The daemon takes due jobs out of the queue while other commands add or change them from other processes
+    fn update<T>(&self, update: impl FnOnce(&mut Vec<QueuedJob>) -> Option<T>) -> Result<Option<T>, RawstErr> {
+        // The queue file itself is replaced on every save, the lock is held on a file next to it
+        let lock_file = fs::File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.file_path.with_added_extension("lock"))
+            .map_err(RawstErr::FileError)?;
+        lock_file.lock().map_err(RawstErr::FileError)?;
+
+        let mut queue = self.get_queue()?;
+        let result = update(&mut queue);
+
+        if result.is_some() {
+            self.save_queue(&queue)?;
+        }
+
+        // Dropping the file releases the lock
+        Ok(result)
+    }
+
+    /// Queues `jobs` to start at `start`, or at the first occurrence of `cron` after it
+    pub fn add_jobs(&self, jobs: Vec<DownloadJob>, start: DateTime<Local>, cron: Option<CronSchedule>) -> Result<Vec<QueuedJob>, RawstErr> {
+        let added = Local::now();
+
+        let next_run = match &cron {
+            Some(cron) => cron.next_after(&start).ok_or(RawstErr::InvalidArgs)?,
+            None => start,
+        };
+
+        let new_jobs: Vec<QueuedJob> = jobs
+            .into_iter()
+            .enumerate()
+            .map(|(index, job)| QueuedJob {
+                // Same scheme as the ids of the history, the index tells apart the jobs added at once
+                id: BASE64_STANDARD.encode(added.timestamp_millis().to_be_bytes()) + &index.to_string(),
+                job,
+                added,
+                next_run,
+                cron: cron.clone(),
+                paused: false,
+            })
+            .collect();
+
+        self.update(|queue| {
+            queue.extend(new_jobs.iter().cloned());
+
+            Some(())
+        })?;
+
+        Ok(new_jobs)
+    }
+
+    /// Takes the jobs due at `now` out of the queue
+    ///
+    /// Recurring jobs stay in it, moved to their next occurrence
+    pub fn take_due(&self, now: &DateTime<Local>) -> Result<Vec<QueuedJob>, RawstErr> {
+        let due = self.update(|queue| {
+            if !queue.iter().any(|queued| queued.is_due(now)) {
+                return None;
+            }
+
+            let (due, mut remaining): (Vec<QueuedJob>, Vec<QueuedJob>) = queue.drain(..).partition(|queued| queued.is_due(now));
+
+            for queued in &due {
+                if let Some(next_run) = queued.cron.as_ref().and_then(|cron| cron.next_after(now)) {
+                    remaining.push(QueuedJob { next_run, ..queued.clone() });
+                }
+            }
+
+            *queue = remaining;
+
+            Some(due)
+        })?;
+
+        Ok(due.unwrap_or_default())
+    }
+
+    /// Time left until the next job is due, none when no job is waiting to start
+    pub fn time_until_next(&self, now: &DateTime<Local>) -> Result<Option<Duration>, RawstErr> {
+        let queue = self.get_queue()?;
+
+        Ok(queue
+            .iter()
+            .find(|queued| !queued.paused)
+            .map(|queued| (queued.next_run - *now).to_std().unwrap_or(Duration::ZERO)))
+    }
+
+    /// Applies `modify` to the queued job with this id, whether there is one
+    pub fn modify_job(&self, id: &str, modify: impl FnOnce(&mut QueuedJob)) -> Result<bool, RawstErr> {
+        let found = self.update(|queue| {
+            let queued = queue.iter_mut().find(|queued| queued.id == id)?;
+            modify(queued);
+
+            Some(())
+        })?;
+
+        Ok(found.is_some())
+    }
+
+    pub fn set_priority(&self, id: &str, priority: i32) -> Result<bool, RawstErr> {
+        self.modify_job(id, |queued| queued.job.priority = priority)
+    }
+
+    pub fn set_paused(&self, id: &str, paused: bool) -> Result<bool, RawstErr> {
+        self.modify_job(id, |queued| {
+            queued.paused = paused;
+
+            // Occurrences missed while paused are skipped rather than started all at once
+            let now = Local::now();
+            if let (false, Some(cron), true) = (paused, &queued.cron, queued.next_run < now) {
+                if let Some(next_run) = cron.next_after(&now) {
+                    queued.next_run = next_run;
+                }
+            }
+        })
+    }
+
+    /// Takes the job out of the queue, whether there was one with this id
+    pub fn cancel_job(&self, id: &str) -> Result<bool, RawstErr> {
+        let found = self.update(|queue| {
+            let index = queue.iter().position(|queued| queued.id == id)?;
+            queue.remove(index);
+
+            Some(())
+        })?;
+
+        Ok(found.is_some())
+    }
+
+    pub fn list_queue(&self) -> Result<(), RawstErr> {
+        let queue = self.get_queue()?;
+
+        if queue.is_empty() {
+            println!("No scheduled downloads");
+
+            return Ok(());
+        }
+
+        let id_width = queue.iter().map(|queued| queued.id.len()).max().unwrap_or(0).max("ID".len());
+        let repeat_width = queue
+            .iter()
+            .map(|queued| queued.cron.as_ref().map_or(1, |cron| cron.to_string().len()))
+            .max()
+            .unwrap_or(0)
+            .max("Repeats".len());
+
+        println!("\n{:<id_width$} | {:<16} | {:<repeat_width$} | {:<8} | {:<6} | IRI", "ID", "Next run", "Repeats", "Priority", "State");
+        println!("{:-<id_width$}-+-{:-<16}-+-{:-<repeat_width$}-+-{:-<8}-+-{:-<6}-+-{:-<7}", "", "", "", "", "", "");
+
+        for queued in &queue {
+            let repeats = queued.cron.as_ref().map_or("-".to_string(), |cron| cron.to_string());
+            let state = match queued.paused {
+                true => "Paused",
+                false => "Queued",
+            };
+
+            println!(
+                "{:<id_width$} | {:<16} | {:<repeat_width$} | {:<8} | {:<6} | {}",
+                queued.id,
+                queued.next_run.format("%Y-%m-%d %H:%M"),
+                repeats,
+                queued.job.priority,
+                state,
+                queued.job.iri
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, NaiveTime};
+use serde::{Deserialize, Serialize};
+
+/// Amount of bytes per second, written as `2M`, `512K` or a plain number of bytes
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ByteRate(pub u64);
+
+impl FromStr for ByteRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let number_end = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+        let (number, unit) = s.split_at(number_end);
+
+        let number = number
+            .parse::<f64>()
+            .map_err(|_| format!("invalid rate '{s}', expected a number of bytes such as 500K or 2M"))?;
+
+        let multiplier: u64 = match unit.trim().to_ascii_uppercase().trim_end_matches("/S").trim_end_matches('B') {
+            "" => 1,
+            "K" => 1024,
+            "M" => 1024 * 1024,
+            "G" => 1024 * 1024 * 1024,
+            _ => return Err(format!("invalid unit '{unit}' in rate '{s}', expected K, M or G")),
+        };
+
+        let rate = (number * multiplier as f64) as u64;
+
+        match rate {
+            0 => Err(format!("rate '{s}' has to be at least one byte per second")),
+            rate => Ok(ByteRate(rate)),
+        }
+    }
+}
+
+impl TryFrom<String> for ByteRate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for ByteRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            rate if rate % (1024 * 1024 * 1024) == 0 => write!(f, "{}G", rate / (1024 * 1024 * 1024)),
+            rate if rate % (1024 * 1024) == 0 => write!(f, "{}M", rate / (1024 * 1024)),
+            rate if rate % 1024 == 0 => write!(f, "{}K", rate / 1024),
+            rate => write!(f, "{}", rate),
+        }
+    }
+}
+
+impl From<ByteRate> for String {
+    fn from(rate: ByteRate) -> Self {
+        rate.to_string()
+    }
+}
+
+/// Time of the day written as `HH:MM`
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(pub NaiveTime);
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NaiveTime::parse_from_str(s.trim(), "%H:%M")
+            .map(TimeOfDay)
+            .map_err(|_| format!("invalid time '{s}', expected HH:MM"))
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.0.format("%H:%M").to_string()
+    }
+}
+
+/// Global bandwidth limit during part of the day, in local time
+///
+/// ```toml
+/// [[rate_schedule]]
+/// from = "22:00"
+/// to = "07:00"
+/// # no limit_rate, full speed at night
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RateSchedule {
+    pub from: TimeOfDay,
+    /// Wraps around midnight when earlier than `from`
+    pub to: TimeOfDay,
+    /// Unlimited when left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit_rate: Option<ByteRate>,
+}
+
+impl RateSchedule {
+    pub fn covers(&self, time: NaiveTime) -> bool {
+        let (from, to) = (self.from.0, self.to.0);
+
+        match from <= to {
+            true => from <= time && time < to,
+            false => time >= from || time < to,
+        }
+    }
+}
+
+/// How often a scheduled limiter looks up the limit of the current time
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    // Unlimited while the schedule says so
+    rate: Option<ByteRate>,
+    rate_checked_at: Instant,
+    // Goes below zero when more was received than allowed, the deficit is waited out
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token bucket holding back the streams sharing it to a steady amount of bytes per second
+///
+/// Up to a second worth of bytes can be received in a burst. With a schedule the rate follows the time of the day
+pub struct RateLimiter {
+    default_rate: Option<ByteRate>,
+    schedule: Vec<RateSchedule>,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: ByteRate) -> Self {
+        RateLimiter::scheduled(Some(rate), Vec::new())
+    }
+
+    /// Limiter following `schedule`, `default_rate` applies outside of it
+    pub fn scheduled(default_rate: Option<ByteRate>, schedule: Vec<RateSchedule>) -> Self {
+        let now = Instant::now();
+        let rate = scheduled_rate(default_rate, &schedule);
+
+        RateLimiter {
+            default_rate,
+            schedule,
+            bucket: Mutex::new(Bucket {
+                rate,
+                rate_checked_at: now,
+                tokens: rate.map_or(0.0, |rate| rate.0 as f64),
+                refilled_at: now,
+            }),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, waiting until they are earned if it ran dry
+    pub async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+
+            if !self.schedule.is_empty() && now.duration_since(bucket.rate_checked_at) >= SCHEDULE_CHECK_INTERVAL {
+                let rate = scheduled_rate(self.default_rate, &self.schedule);
+
+                if rate != bucket.rate {
+                    log::debug!("Bandwidth limit changed to {}", rate.map_or("unlimited".to_string(), |rate| rate.to_string()));
+                    bucket.rate = rate;
+                    bucket.tokens = 0.0;
+                }
+
+                bucket.rate_checked_at = now;
+            }
+
+            let Some(rate) = bucket.rate else {
+                return;
+            };
+            let rate = rate.0 as f64;
+
+            let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+            bucket.refilled_at = now;
+
+            match bucket.tokens < 0.0 {
+                true => Duration::from_secs_f64(-bucket.tokens / rate),
+                false => Duration::ZERO,
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Rate `schedule` gives for the current time, `default_rate` outside of it
+fn scheduled_rate(default_rate: Option<ByteRate>, schedule: &[RateSchedule]) -> Option<ByteRate> {
+    let time = Local::now().time();
+
+    match schedule.iter().find(|schedule| schedule.covers(time)) {
+        Some(schedule) => schedule.limit_rate,
+        None => default_rate,
+    }
+}
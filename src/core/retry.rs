@@ -0,0 +1,133 @@
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use serde::{Deserialize, Serialize};
+
+use crate::core::errors::RawstErr;
+
+/// Decides whether and when a failed chunk request is sent again
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Maximum amount of attempts for each chunk, the first request included
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every following retry
+    pub base_delay_ms: u64,
+    /// Upper bound of the delay between two attempts
+    pub max_delay_ms: u64,
+    /// Fraction of the delay randomly added or removed to spread out retries (0.0 - 1.0)
+    pub jitter: f64,
+    /// HTTP status codes worth retrying, connection errors are always retried
+    pub retryable_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay_ms: 500,
+            max_delay_ms: 30_000,
+            jitter: 0.2,
+            retryable_statuses: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Checks if another attempt is allowed after `attempt` failed attempts ended with `err`
+    pub fn should_retry(&self, attempt: u32, err: &RawstErr) -> bool {
+        if attempt >= self.max_attempts {
+            return false;
+        }
+
+        match err {
+            RawstErr::HttpError(_) | RawstErr::IncompleteChunk => true,
+            _ => match err.status() {
+                Some(status) => self.retryable_statuses.contains(&status.as_u16()),
+                None => false,
+            },
+        }
+    }
+
+    /// Delay before the next attempt, `Retry-After` sent by the server takes precedence
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let max_delay = Duration::from_millis(self.max_delay_ms);
+
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(max_delay);
+        }
+
+        let exponential = self
+            .base_delay_ms
+            .saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1)));
+
+        // No need for a rng crate, the clock is random enough to spread out the retries
+        let random = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.subsec_nanos() as f64 / 1_000_000_000.0)
+            .unwrap_or(0.5);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + jitter * (random * 2.0 - 1.0);
+
+        Duration::from_millis((exponential as f64 * factor) as u64).min(max_delay)
+    }
+}
+
+/// Reads the `Retry-After` header, given either in seconds or as a HTTP date
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+
+    (date - Utc::now()).to_std().ok()
+}
+
+/// Error of a single attempt along with the delay requested by the server
+#[derive(Debug)]
+pub struct AttemptError {
+    pub err: RawstErr,
+    pub retry_after: Option<Duration>,
+}
+
+impl From<RawstErr> for AttemptError {
+    fn from(err: RawstErr) -> Self {
+        AttemptError {
+            err,
+            retry_after: None,
+        }
+    }
+}
+
+/// Runs `attempt` again and again until it succeeds or the policy gives up
+pub async fn with_retries<F, Fut>(policy: &RetryPolicy, description: &str, mut attempt: F) -> Result<(), RawstErr>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), AttemptError>>,
+{
+    let mut failed_attempts = 0;
+
+    loop {
+        let AttemptError { err, retry_after } = match attempt().await {
+            Ok(()) => return Ok(()),
+            Err(attempt_err) => attempt_err,
+        };
+
+        failed_attempts += 1;
+
+        if !policy.should_retry(failed_attempts, &err) {
+            return Err(err);
+        }
+
+        let delay = policy.delay(failed_attempts, retry_after);
+        log::warn!("{description} failed ({err}), retrying in {delay:?} (attempt {}/{})", failed_attempts + 1, policy.max_attempts);
+
+        tokio::time::sleep(delay).await;
+    }
+}
@@ -0,0 +1,139 @@
+//! Control API of the daemon: JSON-RPC 2.0 over its Unix socket, one message per line
+//!
+//! Methods are named after those of aria2 when they do the same, eg. `aria2.addUri` or `aria2.tellStatus`
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+use crate::core::config::Config;
+use crate::core::errors::RawstErr;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    /// Notifications have none and get no response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl Response {
+    pub fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+
+        Response {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn parse_error(message: impl Into<String>) -> Self {
+        RpcError { code: -32700, message: message.into() }
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        RpcError { code: -32600, message: message.into() }
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        RpcError { code: -32601, message: format!("Method {method:?} not found") }
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        RpcError { code: -32602, message: message.into() }
+    }
+
+    /// A request the daemon understood but couldn't carry out, aria2 answers those with code 1 too
+    pub fn failed(message: impl Into<String>) -> Self {
+        RpcError { code: 1, message: message.into() }
+    }
+}
+
+/// Connection to the daemon used by `add`, `pause`, `resume` and `status`
+pub struct ControlClient {
+    reader: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+}
+
+impl ControlClient {
+    /// Connects to the daemon, none when it isn't running
+    pub async fn connect(config: &Config) -> Result<Option<Self>, RawstErr> {
+        let stream = match UnixStream::connect(config.socket_path()).await {
+            Ok(stream) => stream,
+            // The socket of a daemon which didn't stop cleanly is left behind without anyone listening on it
+            Err(err) if matches!(err.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused) => {
+                return Ok(None)
+            }
+            Err(err) => return Err(RawstErr::FileError(err)),
+        };
+
+        let (reader, writer) = stream.into_split();
+
+        Ok(Some(ControlClient {
+            reader: BufReader::new(reader).lines(),
+            writer,
+            next_id: 0,
+        }))
+    }
+
+    pub async fn call(&mut self, method: &str, params: Vec<Value>) -> Result<Value, RawstErr> {
+        log::trace!("Calling {method} (params:{params:?})");
+        self.next_id += 1;
+
+        let request = Request {
+            jsonrpc: "2.0".to_string(),
+            id: Some(Value::from(self.next_id)),
+            method: method.to_string(),
+            params,
+        };
+
+        let mut message = serde_json::to_string(&request).unwrap();
+        message.push('\n');
+
+        self.writer.write_all(message.as_bytes()).await.map_err(RawstErr::FileError)?;
+
+        let line = self
+            .reader
+            .next_line()
+            .await
+            .map_err(RawstErr::FileError)?
+            .ok_or(RawstErr::DaemonError("the daemon closed the connection".to_string()))?;
+
+        let response: Response = serde_json::from_str(&line)
+            .map_err(|err| RawstErr::DaemonError(format!("invalid response: {err}")))?;
+
+        match response.error {
+            Some(error) => Err(RawstErr::DaemonError(error.message)),
+            None => Ok(response.result.unwrap_or_default()),
+        }
+    }
+}
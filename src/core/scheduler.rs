@@ -0,0 +1,253 @@
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::sync::Notify;
+
+struct Waiter {
+    id: u64,
+    priority: i32,
+}
+
+struct SchedulerState {
+    free: usize,
+    waiters: Vec<Waiter>,
+    next_waiter_id: u64,
+    leases: Vec<Weak<ConnectionLease>>,
+}
+
+struct Shared {
+    state: Mutex<SchedulerState>,
+    // Woken up whenever connections are given back or a waiter leaves
+    released: Notify,
+}
+
+impl Shared {
+    fn give_back(&self, connections: usize) {
+        self.state.lock().unwrap().free += connections;
+        self.released.notify_waiters();
+    }
+}
+
+/// Keeps batch downloads within the configured amount of active files and connections
+///
+/// Connections go to the waiting download of the highest priority first, the earliest one among equals.
+/// When it can't get enough of them, downloads of a lower priority are asked to give some back
+pub struct Scheduler {
+    max_downloads: usize,
+    max_connections: usize,
+    shared: Arc<Shared>,
+}
+
+impl Scheduler {
+    pub fn new(max_downloads: usize, max_connections: usize) -> Self {
+
+        Scheduler {
+            max_downloads,
+            max_connections,
+            shared: Arc::new(Shared {
+                state: Mutex::new(SchedulerState {
+                    free: max_connections,
+                    waiters: Vec::new(),
+                    next_waiter_id: 0,
+                    leases: Vec::new(),
+                }),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn max_downloads(&self) -> usize {
+        self.max_downloads
+    }
+
+    /// Waits until enough connections are free for a download using `threads` connections
+    ///
+    /// Downloads asking for more than the global maximum are capped to it.
+    /// The connections are given back once the returned lease is dropped
+    pub async fn acquire_connections(&self, threads: usize, priority: i32) -> Arc<ConnectionLease> {
+        let connections = threads.clamp(1, self.max_connections);
+
+        let id = {
+            let mut state = self.shared.state.lock().unwrap();
+            let id = state.next_waiter_id;
+
+            state.next_waiter_id += 1;
+            state.waiters.push(Waiter { id, priority });
+
+            id
+        };
+
+        // Leaves the line even when the download is dropped while waiting
+        let _waiting = WaiterGuard { shared: &self.shared, id };
+
+        // Set once the downloads of a lower priority have nothing more to give back
+        let mut settle = false;
+
+        loop {
+            let released = self.shared.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let (missing, leases) = {
+                let mut state = self.shared.state.lock().unwrap();
+
+                let first = state
+                    .waiters
+                    .iter()
+                    .min_by_key(|waiter| (Reverse(waiter.priority), waiter.id))
+                    .map(|waiter| waiter.id);
+
+                let granted = match first == Some(id) {
+                    true if state.free >= connections => Some(connections),
+                    true if settle && state.free > 0 => Some(state.free),
+                    _ => None,
+                };
+
+                if let Some(granted) = granted {
+                    state.free -= granted;
+
+                    let lease = Arc::new(ConnectionLease {
+                        priority,
+                        held: AtomicUsize::new(granted),
+                        preempted: AtomicUsize::new(0),
+                        shared: self.shared.clone(),
+                    });
+
+                    state.leases.retain(|lease| lease.strong_count() > 0);
+                    state.leases.push(Arc::downgrade(&lease));
+
+                    return lease;
+                }
+
+                match first == Some(id) {
+                    true => (connections - state.free, state.leases.clone()),
+                    false => (0, Vec::new()),
+                }
+            };
+
+            // Outside of the lock, dropping the last reference to a lease takes it again
+            if missing > 0 {
+                let outstanding = request_preemption(&leases, priority, missing);
+
+                // Rather than waiting for the downloads of a lower priority to finish, it starts with the free connections
+                settle = outstanding == Some(0) && missing < connections;
+
+                if settle {
+                    continue;
+                }
+            }
+
+            released.await;
+        }
+    }
+}
+
+/// Asks the downloads of a lower priority than `priority` to give back `missing` connections, the lowest first
+///
+/// Each download keeps at least one connection. Returns the amount of connections asked back which weren't given
+/// back yet, none when there aren't any downloads of a lower priority
+fn request_preemption(leases: &[Weak<ConnectionLease>], priority: i32, missing: usize) -> Option<usize> {
+    let mut leases: Vec<Arc<ConnectionLease>> = leases
+        .iter()
+        .filter_map(Weak::upgrade)
+        .filter(|lease| lease.priority < priority)
+        .collect();
+    leases.sort_by_key(|lease| lease.priority);
+
+    if leases.is_empty() {
+        return None;
+    }
+
+    // Connections asked back on a previous wake up are on their way already
+    let mut requested: usize = leases.iter().map(|lease| lease.preempted.load(Ordering::SeqCst)).sum();
+
+    for lease in leases {
+        if requested >= missing {
+            break;
+        }
+
+        let spare = lease.held().saturating_sub(1 + lease.preempted.load(Ordering::SeqCst));
+        let taken = spare.min(missing - requested);
+
+        if taken > 0 {
+            log::debug!("Asking a download of priority {} to give back {taken} connections", lease.priority);
+
+            lease.preempted.fetch_add(taken, Ordering::SeqCst);
+            requested += taken;
+        }
+    }
+
+    Some(requested)
+}
+
+struct WaiterGuard<'a> {
+    shared: &'a Shared,
+    id: u64,
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().waiters.retain(|waiter| waiter.id != self.id);
+        // The next in line may be able to start now
+        self.shared.released.notify_waiters();
+    }
+}
+
+/// Connections held by a download, given back to the scheduler when dropped
+///
+/// A waiting download of a higher priority can take some of them away: the workers notice it
+/// between two chunks and the download goes on with fewer connections
+pub struct ConnectionLease {
+    priority: i32,
+    held: AtomicUsize,
+    // Connections asked back by the scheduler which the workers didn't give up yet
+    preempted: AtomicUsize,
+    shared: Arc<Shared>,
+}
+
+impl ConnectionLease {
+    /// Amount of connections the download may use right now
+    pub fn held(&self) -> usize {
+        self.held.load(Ordering::SeqCst)
+    }
+
+    /// Gives back the connections beyond `connections`, for downloads which can't use all of them
+    pub fn shrink_to(&self, connections: usize) {
+        let connections = connections.max(1);
+
+        if let Ok(held) = self.held.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |held| (held > connections).then_some(connections)) {
+            self.shared.give_back(held - connections);
+        }
+    }
+
+    /// Gives back the connection of a worker which stopped, the last one is kept until the lease is dropped
+    pub fn release_one(&self) -> bool {
+        match self.held.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |held| (held > 1).then(|| held - 1)) {
+            Ok(_) => {
+                self.shared.give_back(1);
+
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Called by a worker between two pieces of a chunk, whether it should stop and give its connection back
+    ///
+    /// The last connection is never taken
+    pub fn take_preemption(&self) -> bool {
+        let preempted = self
+            .preempted
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |preempted| preempted.checked_sub(1))
+            .is_ok();
+
+        preempted && self.release_one()
+    }
+}
+
+impl Drop for ConnectionLease {
+    fn drop(&mut self) {
+        self.shared.give_back(self.held());
+    }
+}
@@ -1,54 +1,214 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
 };
 
 use iri_string::types::IriString;
 use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
 use chrono::prelude::{Local, DateTime};
-use sha2::{Sha256, Digest};
 
-#[derive(Clone, Debug)]
+use crate::core::checksum::Checksum;
+use crate::core::config::StorageMode;
+use crate::core::errors::RawstErr;
+use crate::core::io::SegmentState;
+use crate::core::rate_limit::{ByteRate, RateLimiter};
+use crate::core::scheduler::ConnectionLease;
+use crate::core::utils::{hash_file_name, LinkOptions};
+
+/// A single download requested by the user, before anything is fetched from the server
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DownloadJob {
+    pub iri: IriString,
+    pub save_as: Option<PathBuf>,
+    pub headers: HashMap<String, String>,
+    pub threads: usize,
+    pub checksum: Option<Checksum>,
+    pub limit_rate: Option<ByteRate>,
+    /// Jobs of a higher priority start first and can take connections away from the others
+    #[serde(default)]
+    pub priority: i32,
+}
+
+impl DownloadJob {
+    pub fn new(iri: IriString, headers: HashMap<String, String>, threads: usize) -> Self {
+        DownloadJob {
+            iri,
+            save_as: None,
+            headers,
+            threads,
+            checksum: None,
+            limit_rate: None,
+            priority: 0,
+        }
+    }
+
+    /// Creates a job from the options of a links file line
+    ///
+    /// - `out=<PATH>` custom output file path
+    /// - `threads=<N>` amount of threads used for this download, capped by the config when it starts
+    /// - `checksum=<ALGORITHM>:<HEX>` expected checksum of the downloaded file
+    /// - `limit-rate=<RATE>` bandwidth of this download, eg. `500K` or `2M`
+    /// - `priority=<N>` priority of this download, higher ones start first
+    pub fn from_link_options(
+        iri: IriString,
+        options: LinkOptions,
+        headers: &HashMap<String, String>,
+        threads: usize,
+        priority: i32
+    ) -> Result<Self, RawstErr> {
+        let mut job = DownloadJob::new(iri, headers.to_owned(), threads);
+        job.priority = priority;
+
+        for (key, value) in options {
+            match key.as_str() {
+                "out" => job.save_as = Some(PathBuf::from(value)),
+                "threads" => {
+                    job.threads = value
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|threads| *threads >= 1)
+                        .ok_or(RawstErr::InvalidLink(format!("invalid thread count '{value}'")))?;
+                }
+                "checksum" => job.checksum = Some(value.parse::<Checksum>().map_err(RawstErr::InvalidLink)?),
+                "limit-rate" => job.limit_rate = Some(value.parse::<ByteRate>().map_err(RawstErr::InvalidLink)?),
+                "priority" => {
+                    job.priority = value
+                        .parse::<i32>()
+                        .map_err(|_| RawstErr::InvalidLink(format!("invalid priority '{value}'")))?;
+                }
+                _ => return Err(RawstErr::InvalidLink(format!("unknown option '{key}'"))),
+            }
+        }
+
+        Ok(job)
+    }
+}
+
+#[derive(Debug)]
 pub struct Chunk {
     pub x_offset: u64, // x offset is starting byte
-    pub y_offset: u64, // y offset is end byte
+    y_offset: AtomicU64, // y offset is end byte, moved back when the chunk is split
 
     pub downloaded: Arc<AtomicU64>, // downloaded bytes of a chunk
+    persisted: AtomicU64, // downloaded bytes known to be on disk, the only ones the control file may claim
+
+    // Whether a worker already took the chunk
+    claimed: AtomicBool,
 }
 
 impl Chunk {
     pub fn new(x_offset: u64, y_offset: u64) -> Self {
         Chunk {
             x_offset,
-            y_offset,
+            y_offset: AtomicU64::new(y_offset),
             downloaded: Arc::new(AtomicU64::new(0)),
+            persisted: AtomicU64::new(0),
+            claimed: AtomicBool::new(false),
         }
     }
 
+    /// Last byte of the chunk
+    pub fn y_offset(&self) -> u64 {
+        self.y_offset.load(Ordering::SeqCst)
+    }
+
+    /// Byte the next request for this chunk has to start from
+    pub fn current_offset(&self) -> u64 {
+        self.x_offset + self.downloaded.load(Ordering::SeqCst)
+    }
+
+    /// Amount of bytes covered by the chunk, (y_offset - x_offset) + 1
+    pub fn size(&self) -> u64 {
+        (self.y_offset() + 1).saturating_sub(self.x_offset)
+    }
+
+    /// Amount of bytes of the chunk which are still missing
+    pub fn remaining(&self) -> u64 {
+        self.size().saturating_sub(self.downloaded.load(Ordering::SeqCst))
+    }
+
+    /// Amount of downloaded bytes which were synced to disk
+    pub fn persisted(&self) -> u64 {
+        self.persisted.load(Ordering::SeqCst)
+    }
+
+    /// Records every downloaded byte as on disk, only called by the worker of the chunk once its file is synced
+    pub fn mark_persisted(&self) {
+        self.persisted.store(self.downloaded.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    /// Sets the progress of a chunk found on disk when a download is resumed
+    pub fn restore_downloaded(&self, downloaded: u64) {
+        self.downloaded.store(downloaded, Ordering::SeqCst);
+        self.persisted.store(downloaded, Ordering::SeqCst);
+    }
+
     pub fn is_downloaded(&self) -> bool {
-        // Each chunk file size is usually the difference of x and y offset added by 1
-        // (y_offset - x_offset) + 1
-        self.x_offset == self.y_offset + 1
+        self.downloaded.load(Ordering::SeqCst) >= self.size()
+    }
+
+    /// Whether a worker took the chunk and still has bytes left to download
+    pub fn in_progress(&self) -> bool {
+        self.claimed.load(Ordering::SeqCst) && !self.is_downloaded()
+    }
+
+    /// Gives the chunk back so another worker can take it over
+    pub fn release(&self) {
+        self.claimed.store(false, Ordering::SeqCst);
+    }
+
+    /// Cuts off the second half of the missing bytes as a new chunk, if both halves keep at least `min_size` bytes
+    fn split(&self, min_size: u64) -> Option<Chunk> {
+        let y_offset = self.y_offset();
+        let remaining = self.remaining();
+
+        // Both halves need a byte at least
+        if remaining < 2 * min_size.max(1) {
+            return None;
+        }
+
+        let x_offset = self.current_offset() + remaining / 2;
+        self.y_offset.store(x_offset - 1, Ordering::SeqCst);
+
+        Some(Chunk::new(x_offset, y_offset))
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub enum ChunkType {
-    Single(Chunk),
-    Multiple(Vec<Chunk>),
+    Single(Arc<Chunk>),
+    // Grows as chunks are split, a chunk keeps its index for its whole life
+    Multiple(Mutex<Vec<Arc<Chunk>>>),
     None,
 }
 
-#[derive(Clone)]
 pub struct HttpTask {
     pub iri: IriString,
     pub filename: PathBuf,
+    pub download_dir: PathBuf,
     pub total_downloaded: Arc<AtomicU64>,
     pub chunk_data: ChunkType,
+    /// Amount of chunks downloaded at the same time
+    pub connections: usize,
+    /// Smallest amount of bytes a chunk is split into
+    pub min_segment_size: u64,
+    /// Bandwidth of this download alone
+    pub limit_rate: Option<ByteRate>,
+    /// Every limit the bandwidth of the download is held back to, shared ones included
+    pub rate_limiters: Vec<Arc<RateLimiter>>,
+    /// Connections given by the scheduler, none when the download doesn't go through it
+    pub connection_lease: Option<Arc<ConnectionLease>>,
+    /// Headers given for the download, stored in its history record
     pub additional_headers: HashMap<String, String>,
+    /// Headers of the policy of the host, taken from the config on every run and never stored since they may hold secrets
+    pub host_headers: HashMap<String, String>,
     pub timestamp: DateTime<Local>,
+    pub storage: StorageMode,
+    pub checksum: Option<Checksum>,
+    pub checksum_source: Option<String>,
 
     // Cached headermap from Head request
     // Efficient for header values retrieval
@@ -59,8 +219,8 @@ impl std::fmt::Debug for HttpTask {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "HttpTask{{iri:{}, filename:{:?}, ...}}",
-            self.iri, self.filename
+            "HttpTask{{iri:{}, filename:{:?}, download_dir:{:?}, ...}}",
+            self.iri, self.filename, self.download_dir
         )
     }
 }
@@ -69,6 +229,7 @@ impl HttpTask {
     pub fn new(
         iri: IriString,
         filename: PathBuf,
+        download_dir: PathBuf,
         cached_headers: HeaderMap,
         additional_headers: HashMap<String, String>
     ) -> Self {
@@ -79,101 +240,178 @@ impl HttpTask {
         HttpTask {
             iri,
             filename,
+            download_dir,
             headers: cached_headers,
             total_downloaded: Arc::new(AtomicU64::new(0)),
             chunk_data,
+            connections: 1,
+            min_segment_size: 0,
+            limit_rate: None,
+            rate_limiters: Vec::new(),
+            connection_lease: None,
             additional_headers,
+            host_headers: HashMap::new(),
             timestamp: Local::now(),
+            storage: StorageMode::default(),
+            checksum: None,
+            checksum_source: None,
         }
     }
 
-    pub fn hashed_file_name(&self) -> String {
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
 
-        let formatted_string = format!("{}{}", self.iri, self.timestamp);
+    /// Headers sent with every request of the download, those given for it take precedence over the ones of its host
+    pub fn request_headers(&self) -> HashMap<String, String> {
+        let mut headers = self.host_headers.clone();
+        headers.extend(self.additional_headers.clone());
 
-        let mut hasher = Sha256::new();
+        headers
+    }
 
-        hasher.update(formatted_string.as_bytes());
+    pub fn hashed_file_name(&self) -> String {
 
-        format!("{:x}", hasher.finalize())
+        hash_file_name(&self.iri, &self.timestamp.to_string())
 
     }
 
-    pub fn allocate_chunks(&mut self, number_of_chunks: usize) {
+    /// Holds on to the connections of the scheduler while downloading, giving back the ones the chunks can't use
+    pub fn attach_lease(&mut self, lease: Arc<ConnectionLease>) {
+        lease.shrink_to(self.connections);
+        self.connection_lease = Some(lease);
+    }
 
-        // Allocates chunk space depending on number of threads 
-        if number_of_chunks == 1 {
-            self.chunk_data = ChunkType::Single(Chunk::new(0, 0));
-        }
-        else {
-            self.chunk_data = ChunkType::Multiple(Vec::with_capacity(number_of_chunks));
+    /// Most workers the download may run right now
+    pub fn connection_limit(&self) -> usize {
+        self.connection_lease.as_ref().map_or(usize::MAX, |lease| lease.held())
+    }
+
+    /// Whether a worker should stop, giving its connection to a download of a higher priority
+    pub fn take_preemption(&self) -> bool {
+        self.connection_lease.as_ref().is_some_and(|lease| lease.take_preemption())
+    }
+
+    /// Gives the connection of a worker which ran out of work back to the scheduler
+    pub fn release_connection(&self) {
+        if let Some(lease) = &self.connection_lease {
+            lease.release_one();
         }
+    }
 
+    /// Amount of chunks of the download, which grows as chunks are split
+    pub fn threads(&self) -> usize {
+        match &self.chunk_data {
+            ChunkType::Multiple(chunks) => chunks.lock().unwrap().len(),
+            _ => 1,
+        }
     }
 
     pub fn calculate_chunks(&mut self, number_of_chunks: u64) {
-        let total_size = self.content_length();
+        // Files of unknown size are streamed in a single chunk
+        let total_size = self.content_length().unwrap_or(0);
+        // Offsets are inclusive so the last chunk ends on the last byte
+        let last_byte = total_size.saturating_sub(1);
+        // Every chunk needs at least one byte
+        let number_of_chunks = number_of_chunks.clamp(1, total_size.max(1));
 
-        self.allocate_chunks(number_of_chunks as usize);
+        let chunk_size = total_size / number_of_chunks;
 
-        match &mut self.chunk_data {
-            ChunkType::Single(chunk) => {
-                chunk.x_offset = 0;
-                chunk.y_offset = total_size;
-            }
-            ChunkType::Multiple(chunks) => {
-                let chunk_size = total_size / number_of_chunks;
-
-                (0..number_of_chunks).for_each(|i| match i {
-                    0 => {
-                        chunks.push(Chunk::new(0, chunk_size));
-                    }
-                    last_chunk if last_chunk == number_of_chunks - 1 => {
-                        let start = chunks[(i - 1) as usize].y_offset + 1;
-                        let end = total_size;
-
-                        chunks.push(Chunk::new(start, end));
-                    }
-                    _ => {
-                        let start = chunks[(i - 1) as usize].y_offset + 1;
-                        let end = start + chunk_size;
-
-                        chunks.push(Chunk::new(start, end));
-                    }
-                });
-            }
-            ChunkType::None => (),
+        let chunks = (0..number_of_chunks)
+            .map(|i| {
+                let start = i * chunk_size;
+                let end = match i == number_of_chunks - 1 {
+                    true => last_byte,
+                    false => start + chunk_size - 1,
+                };
+
+                Chunk::new(start, end)
+            })
+            .collect();
+
+        self.connections = number_of_chunks as usize;
+        self.set_chunks(chunks);
+    }
+
+    /// Marks the bytes already present in the cache files as downloaded
+    pub fn set_downloaded(&self, cache_sizes: &[u64]) {
+        for (chunk, value) in self.chunks().iter().zip(cache_sizes) {
+            chunk.restore_downloaded(*value);
         }
     }
 
-    pub fn calculate_x_offsets(&mut self, offsets: &[u64]) {
-        match &mut self.chunk_data {
-            ChunkType::Single(chunk) => {
-                chunk.x_offset += offsets[0];
-                chunk.downloaded.fetch_add(offsets[0], Ordering::SeqCst);
-            }
-            ChunkType::Multiple(chunks) => {
-                for (index, value) in offsets.iter().enumerate() {
-                    chunks[index]
-                        .downloaded
-                        .fetch_add(offsets[index], Ordering::SeqCst);
+    /// Rebuilds the chunks exactly as they were saved in a control file
+    pub fn restore_chunks(&mut self, segments: &[SegmentState]) {
+        let chunks = segments
+            .iter()
+            .map(|segment| {
+                let chunk = Chunk::new(segment.x_offset, segment.y_offset);
+                chunk.restore_downloaded(segment.downloaded);
 
-                    chunks[index].x_offset += *value;
-                }
-            }
-            ChunkType::None => (),
+                chunk
+            })
+            .collect();
+
+        self.set_chunks(chunks);
+    }
+
+    fn set_chunks(&mut self, mut chunks: Vec<Chunk>) {
+        self.chunk_data = match chunks.len() {
+            0 => ChunkType::None,
+            1 => ChunkType::Single(Arc::new(chunks.pop().unwrap())),
+            _ => ChunkType::Multiple(Mutex::new(chunks.into_iter().map(Arc::new).collect())),
+        };
+    }
+
+    /// Every chunk of the download in the order they were created, which isn't the order of the file
+    pub fn chunks(&self) -> Vec<Arc<Chunk>> {
+        match &self.chunk_data {
+            ChunkType::Single(chunk) => vec![chunk.clone()],
+            ChunkType::Multiple(chunks) => chunks.lock().unwrap().clone(),
+            ChunkType::None => Vec::new(),
         }
     }
 
-    pub fn content_length(&self) -> u64 {
-        match self.headers.get("content-length") {
-            Some(length) => length
-                .to_str()
-                .unwrap()
-                .parse()
-                .expect("Invalid size format"),
-            None => 0,
+    /// Hands a worker the next chunk to download along with its index
+    ///
+    /// Chunks nobody took yet come first. After that the chunk with the most missing bytes is split
+    /// and its second half handed out, so no connection sits idle while another one still has work left
+    pub fn next_chunk(&self) -> Option<(usize, Arc<Chunk>)> {
+        let ChunkType::Multiple(chunks) = &self.chunk_data else {
+            return None;
+        };
+
+        let mut chunks = chunks.lock().unwrap();
+
+        for (chunk_number, chunk) in chunks.iter().enumerate() {
+            if !chunk.is_downloaded() && !chunk.claimed.swap(true, Ordering::SeqCst) {
+                return Some((chunk_number, chunk.clone()));
+            }
         }
+
+        let largest = chunks.iter().max_by_key(|chunk| chunk.remaining())?;
+        let tail = Arc::new(largest.split(self.min_segment_size)?);
+        log::trace!("Split {largest:?}, handing out {tail:?}");
+
+        tail.claimed.store(true, Ordering::SeqCst);
+        chunks.push(tail.clone());
+
+        Some((chunks.len() - 1, tail))
+    }
+
+    /// Whether a chunk was given back with bytes left and no worker took it over yet
+    pub fn has_unclaimed_chunk(&self) -> bool {
+        self.chunks()
+            .iter()
+            .any(|chunk| !chunk.is_downloaded() && !chunk.claimed.load(Ordering::SeqCst))
+    }
+
+    /// Size of the file, unknown for chunked transfers and dynamic content
+    pub fn content_length(&self) -> Option<u64> {
+        self.headers
+            .get("content-length")
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse().ok())
     }
 
     pub fn allows_partial_content(&self) -> bool {
@@ -182,4 +420,41 @@ impl HttpTask {
             None => false,
         }
     }
+
+    /// Starts the download over from nothing with the headers of the current version of the file
+    pub fn restart(&mut self, cached_headers: HeaderMap) {
+        self.headers = cached_headers;
+
+        let threads = match self.allows_partial_content() {
+            true => self.connections as u64,
+            false => 1,
+        };
+
+        self.calculate_chunks(threads);
+        self.total_downloaded.store(0, Ordering::SeqCst);
+    }
+
+    pub fn etag(&self) -> Option<String> {
+        self.header_value("etag")
+    }
+
+    pub fn last_modified(&self) -> Option<String> {
+        self.header_value("last-modified")
+    }
+
+    /// Validator sent as `If-Range` so a changed file is sent whole instead of spliced into the old one
+    ///
+    /// Weak ETags can't be used for ranges, `Last-Modified` is used instead
+    pub fn if_range(&self) -> Option<String> {
+        self.etag()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or_else(|| self.last_modified())
+    }
+
+    fn header_value(&self, name: &str) -> Option<String> {
+        self.headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    }
 }
@@ -1,10 +1,11 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs;
 
 use iri_string::types::IriString;
 use serde_json::Value;
 use reqwest::header::HeaderMap;
+use sha2::{Digest, Sha256};
 
 use crate::core::errors::RawstErr;
 
@@ -29,6 +30,53 @@ pub fn headers_from_file(input: PathBuf) -> Result<HashMap<String, String>, Raws
     Ok(header_map)
 }
 
+/// Output paths within a directory are made absolute, bare file names still go to the download directory
+///
+/// Needed whenever the download starts from another working directory, eg. in the daemon or from the queue
+pub fn resolve_output_path(path: &Path) -> Result<PathBuf, RawstErr> {
+    match path.parent().is_some_and(|parent| !parent.as_os_str().is_empty()) {
+        true => std::path::absolute(path).map_err(RawstErr::FileError),
+        false => Ok(path.to_path_buf()),
+    }
+}
+
+/// Options given after the IRI on a links file line
+pub type LinkOptions = HashMap<String, String>;
+
+/// Parses a line of a links file
+///
+/// Each line holds an IRI optionally followed by whitespace separated `key=value` options
+/// eg. `https://example.com/file.iso out=custom_name.iso threads=4`
+///
+/// Blank lines and lines starting with `#` are ignored
+pub fn parse_link_line(line: &str) -> Result<Option<(IriString, LinkOptions)>, RawstErr> {
+    let line = line.trim();
+
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let mut parts = line.split_whitespace();
+
+    let iri = parts
+        .next()
+        .unwrap()
+        .parse::<IriString>()
+        .map_err(|err| RawstErr::InvalidLink(err.to_string()))?;
+
+    let mut options = LinkOptions::new();
+
+    for part in parts {
+        let (key, value) = part
+            .split_once('=')
+            .ok_or(RawstErr::InvalidLink(format!("expected `key=value` option, found '{part}'")))?;
+
+        options.insert(key.to_string(), value.to_string());
+    }
+
+    Ok(Some((iri, options)))
+}
+
 pub fn extract_filename_from_url(iri: &IriString) -> PathBuf {
     // "http://example.com/path/to/file.tar.gz?query#frag"
     // => "/path/to/file.tar.gz"
@@ -48,6 +96,16 @@ pub fn extract_filename_from_url(iri: &IriString) -> PathBuf {
     path
 }
 
+/// Builds the IRI of a file sitting next to the one of `iri`, eg. `file.iso.sha256` or `SHA256SUMS`
+pub fn sibling_iri(iri: &IriString, sibling_name: &str) -> Option<IriString> {
+    let path = iri.path_str();
+    let directory = &path[..path.rfind('/')? + 1];
+
+    format!("{}://{}{}{}", iri.scheme_str(), iri.authority_str()?, directory, sibling_name)
+        .parse::<IriString>()
+        .ok()
+}
+
 pub fn extract_filename_from_header(headers: &HeaderMap) -> Option<PathBuf> {
     let header_value = headers.get("Content-Disposition");
 
@@ -69,8 +127,33 @@ pub fn extract_filename_from_header(headers: &HeaderMap) -> Option<PathBuf> {
     }
 }
 
+/// Name the cache files of a download are derived from, unique to the IRI and the time it started
+pub fn hash_file_name(iri: &IriString, timestamp: &str) -> String {
+
+    let formatted_string = format!("{}{}", iri, timestamp);
+
+    let mut hasher = Sha256::new();
+
+    hasher.update(formatted_string.as_bytes());
+
+    format!("{:x}", hasher.finalize())
+
+}
+
 pub fn chunk_file_name(hashed_filename: String, part: usize) -> PathBuf {
 
     PathBuf::from(hashed_filename).with_added_extension(format!("part{}", part))
 
 }
+
+pub fn preallocated_file_name(hashed_filename: String) -> PathBuf {
+
+    PathBuf::from(hashed_filename).with_added_extension("download")
+
+}
+
+pub fn control_file_name(hashed_filename: String) -> PathBuf {
+
+    PathBuf::from(hashed_filename).with_added_extension("ctrl")
+
+}
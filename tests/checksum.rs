@@ -0,0 +1,61 @@
+mod common;
+
+use std::collections::HashMap;
+
+use iri_string::types::IriString;
+use rawst_dl::core::engine::Engine;
+use rawst_dl::core::history::Record;
+use rawst_dl::core::task::DownloadJob;
+
+use common::{file_names, test_body, test_config, ServerOptions, TestServer};
+
+const BODY_SIZE: usize = 300_000;
+
+fn job(url: &str) -> DownloadJob {
+    DownloadJob::new(IriString::try_from(url).unwrap(), HashMap::new(), 1)
+}
+
+#[tokio::test]
+async fn verifies_against_content_digest_of_head() {
+    let body = test_body(BODY_SIZE);
+    let options = ServerOptions {
+        content_digest: true,
+        ..ServerOptions::default()
+    };
+    let server = TestServer::start(body.clone(), options).await;
+
+    let mut config = test_config("head-digest", 1);
+    config.auto_checksum = true;
+    let history_file_path = config.history_file_path.clone();
+    let download_dir = config.download_dir.clone();
+
+    Engine::new(config).process_batch(vec![job(&server.url("/file.bin"))]).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("file.bin")).unwrap(), body);
+
+    let records: Vec<Record> = serde_json::from_str(&std::fs::read_to_string(history_file_path).unwrap()).unwrap();
+    assert_eq!(records[0].status, "Completed");
+    assert_eq!(records[0].checksum_source.as_deref(), Some("content-digest header"));
+}
+
+#[tokio::test]
+async fn ignores_content_digest_of_ranged_probe() {
+    let body = test_body(BODY_SIZE);
+    let options = ServerOptions {
+        reject_head: true,
+        content_digest: true,
+        ..ServerOptions::default()
+    };
+    let server = TestServer::start(body.clone(), options).await;
+
+    let mut config = test_config("probe-digest", 1);
+    config.auto_checksum = true;
+    let download_dir = config.download_dir.clone();
+
+    // The digest of the 206 answering the probe only covers its first byte
+    Engine::new(config).process_batch(vec![job(&server.url("/file.bin"))]).await.unwrap();
+
+    assert_eq!(server.requests("GET")[0].range.as_deref(), Some("bytes=0-0"));
+    assert_eq!(std::fs::read(download_dir.join("file.bin")).unwrap(), body);
+    assert_eq!(file_names(&download_dir), vec!["file.bin"]);
+}
@@ -0,0 +1,311 @@
+//! Helpers shared by the integration tests: a local HTTP server with misbehaviours on demand
+//! and a config writing everything into a temporary directory
+
+#![allow(dead_code)]
+
+pub mod proxy;
+
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use rawst_dl::core::adaptive::AdaptivePolicy;
+use rawst_dl::core::config::{Config, RemoteChangePolicy, StorageMode};
+use rawst_dl::core::retry::RetryPolicy;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Size of the pieces of a chunked response
+const CHUNK_SIZE: usize = 8 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct ServedRequest {
+    pub method: String,
+    pub path: String,
+    pub range: Option<String>,
+    pub if_range: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ServerOptions {
+    /// Sends `Content-Length`, otherwise bodies are sent with chunked transfer encoding
+    pub content_length: bool,
+    /// Amount of GET requests whose connection is dropped halfway through the body
+    pub drop_first: usize,
+    /// Answers HEAD requests with `405 Method Not Allowed`, like the CDNs which only allow GET
+    pub reject_head: bool,
+    /// Sends the `Content-Digest` of the body of each response, which is only a part of the file for a range
+    pub content_digest: bool,
+    /// Advertises ranges but answers every GET with the whole file
+    pub ignore_ranges: bool,
+    /// Sends a `Content-Range` starting a byte after the requested one
+    pub misplaced_range: bool,
+    /// `ETag` of the file, a range guarded by another one in `If-Range` is answered with the whole file
+    pub etag: Option<String>,
+    /// Another version of the file, served from then on as if it changed on the server
+    pub changed: Option<ChangedFile>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            content_length: true,
+            drop_first: 0,
+            reject_head: false,
+            content_digest: false,
+            ignore_ranges: false,
+            misplaced_range: false,
+            etag: None,
+            changed: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ChangedFile {
+    /// Amount of GET requests answered with the original file first
+    pub after: usize,
+    pub body: Vec<u8>,
+    pub etag: Option<String>,
+}
+
+/// HTTP/1.1 server serving `body` on every path, with support for `Range`
+pub struct TestServer {
+    pub address: String,
+    requests: Arc<Mutex<Vec<ServedRequest>>>,
+}
+
+impl TestServer {
+    pub async fn start(body: Vec<u8>, options: ServerOptions) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let body = Arc::new(body);
+        let gets = Arc::new(AtomicUsize::new(0));
+
+        let served = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+
+                let (body, options, served, gets) = (body.clone(), options.clone(), served.clone(), gets.clone());
+                tokio::spawn(async move {
+                    let _ = serve(stream, &body, &options, &served, &gets).await;
+                });
+            }
+        });
+
+        TestServer { address, requests }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.address, path)
+    }
+
+    pub fn requests(&self, method: &str) -> Vec<ServedRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.method == method)
+            .cloned()
+            .collect()
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    body: &[u8],
+    options: &ServerOptions,
+    served: &Mutex<Vec<ServedRequest>>,
+    gets: &AtomicUsize,
+) -> std::io::Result<()> {
+    let head = read_head(&mut stream).await?;
+
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+
+    let header = |header_name: &str| {
+        head.lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(header_name))
+            .map(|(_, value)| value.trim().to_string())
+    };
+    let range = header("range");
+    let if_range = header("if-range");
+
+    served.lock().unwrap().push(ServedRequest {
+        method: method.clone(),
+        path,
+        range: range.clone(),
+        if_range: if_range.clone(),
+    });
+
+    if method == "HEAD" && options.reject_head {
+        stream.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await?;
+        return stream.shutdown().await;
+    }
+
+    // HEAD describes the version of the file the next GET gets
+    let request_number = match method.as_str() {
+        "HEAD" => gets.load(Ordering::SeqCst),
+        _ => gets.fetch_add(1, Ordering::SeqCst),
+    };
+
+    let (body, etag) = match &options.changed {
+        Some(changed) if request_number >= changed.after => (changed.body.as_slice(), changed.etag.as_ref()),
+        _ => (body, options.etag.as_ref()),
+    };
+
+    // A range of another version of the file is answered with the whole current one
+    let range = match (&range, &if_range) {
+        (Some(_), Some(validator)) if Some(validator) != etag => None,
+        _ if options.ignore_ranges && method != "HEAD" => None,
+        _ => range,
+    };
+
+    let total = body.len();
+    let (start, end) = match range.as_deref().and_then(parse_range) {
+        Some((start, end)) => (start, end.unwrap_or(total - 1).min(total - 1)),
+        None => (0, total - 1),
+    };
+
+    let mut response = match (range, options.misplaced_range) {
+        (Some(_), false) => format!("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{end}/{total}\r\n"),
+        (Some(_), true) => format!("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{end}/{total}\r\n", start + 1),
+        (None, _) => "HTTP/1.1 200 OK\r\n".to_string(),
+    };
+    response.push_str("Accept-Ranges: bytes\r\nConnection: close\r\n");
+
+    if let Some(etag) = etag {
+        response.push_str(&format!("ETag: {etag}\r\n"));
+    }
+
+    let content = &body[start..=end];
+
+    if options.content_digest {
+        let digest = BASE64_STANDARD.encode(Sha256::digest(content));
+        response.push_str(&format!("Content-Digest: sha-256=:{digest}:\r\n"));
+    }
+
+    match (options.content_length, method.as_str()) {
+        (true, "HEAD") => response.push_str(&format!("Content-Length: {total}\r\n\r\n")),
+        (true, _) => response.push_str(&format!("Content-Length: {}\r\n\r\n", content.len())),
+        (false, _) => response.push_str("Transfer-Encoding: chunked\r\n\r\n"),
+    }
+
+    stream.write_all(response.as_bytes()).await?;
+
+    if method == "HEAD" {
+        return stream.shutdown().await;
+    }
+
+    // Dropping the connection halfway leaves the client with a truncated body
+    let drop_halfway = request_number < options.drop_first;
+    let content = match drop_halfway {
+        true => &content[..content.len() / 2],
+        false => content,
+    };
+
+    match options.content_length {
+        true => stream.write_all(content).await?,
+        false => {
+            for piece in content.chunks(CHUNK_SIZE) {
+                stream.write_all(format!("{:x}\r\n", piece.len()).as_bytes()).await?;
+                stream.write_all(piece).await?;
+                stream.write_all(b"\r\n").await?;
+            }
+
+            if !drop_halfway {
+                stream.write_all(b"0\r\n\r\n").await?;
+            }
+        }
+    }
+
+    stream.shutdown().await
+}
+
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+
+        if read == 0 {
+            break;
+        }
+
+        head.extend_from_slice(&buffer[..read]);
+    }
+
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+/// Parses `bytes=<start>-[end]`
+fn parse_range(value: &str) -> Option<(usize, Option<usize>)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+
+    Some((start.parse().ok()?, end.parse().ok()))
+}
+
+/// Deterministic content which makes misplaced bytes easy to spot
+pub fn test_body(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8).collect()
+}
+
+/// Config writing the cache, history and downloads into a fresh temporary directory
+pub fn test_config(name: &str, max_attempts: u32) -> Config {
+    let root = std::env::temp_dir().join(format!("rawst-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let cache_dir = root.join("cache");
+    let download_dir = root.join("downloads");
+    std::fs::create_dir_all(&cache_dir).unwrap();
+    std::fs::create_dir_all(&download_dir).unwrap();
+
+    let history_file_path = cache_dir.join("history.json");
+    std::fs::write(&history_file_path, "[\n\n]").unwrap();
+
+    Config {
+        config_dir: root.clone(),
+        config_file_path: root.join("config.toml"),
+        cache_dir: cache_dir.clone(),
+        history_file_path,
+        log_dir: cache_dir.join("logs"),
+        download_dir,
+        threads: 1,
+        max_threads: 8,
+        max_downloads: 1,
+        max_connections: 8,
+        min_segment_size: 1024 * 1024,
+        auto_checksum: false,
+        storage: StorageMode::default(),
+        on_remote_change: RemoteChangePolicy::default(),
+        limit_rate: None,
+        proxy: None,
+        proxy_credentials: None,
+        retry: RetryPolicy {
+            max_attempts,
+            base_delay_ms: 10,
+            ..RetryPolicy::default()
+        },
+        adaptive: AdaptivePolicy::default(),
+        hosts: Vec::new(),
+        rate_schedule: Vec::new(),
+    }
+}
+
+/// Names of the files left in a directory
+pub fn file_names(directory: &Path) -> Vec<String> {
+    std::fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect()
+}
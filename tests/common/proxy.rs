@@ -0,0 +1,184 @@
+//! Local stand-ins for a forwarding HTTP proxy and a SOCKS5 proxy, relaying to the test server
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::read_head;
+
+#[derive(Clone, Debug)]
+pub struct ProxiedRequest {
+    /// Request target of the HTTP proxy, `host:port` asked to the SOCKS5 proxy
+    pub target: String,
+    /// `Proxy-Authorization` sent to the HTTP proxy, username given to the SOCKS5 proxy
+    pub credentials: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Protocol {
+    Http,
+    Socks5,
+}
+
+pub struct TestProxy {
+    pub address: String,
+    protocol: Protocol,
+    requests: Arc<Mutex<Vec<ProxiedRequest>>>,
+}
+
+impl TestProxy {
+    /// Proxy forwarding requests given with an absolute URI
+    pub async fn http() -> Self {
+        TestProxy::start(Protocol::Http).await
+    }
+
+    /// SOCKS5 proxy accepting any username and password, or none
+    pub async fn socks5() -> Self {
+        TestProxy::start(Protocol::Socks5).await
+    }
+
+    async fn start(protocol: Protocol) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let proxied = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+
+                let proxied = proxied.clone();
+                tokio::spawn(async move {
+                    let _ = match protocol {
+                        Protocol::Http => relay_http(stream, &proxied).await,
+                        Protocol::Socks5 => relay_socks5(stream, &proxied).await,
+                    };
+                });
+            }
+        });
+
+        TestProxy { address, protocol, requests }
+    }
+
+    pub fn url(&self) -> String {
+        match self.protocol {
+            Protocol::Http => format!("http://{}", self.address),
+            Protocol::Socks5 => format!("socks5h://{}", self.address),
+        }
+    }
+
+    pub fn requests(&self) -> Vec<ProxiedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn relay_http(mut stream: TcpStream, proxied: &Mutex<Vec<ProxiedRequest>>) -> std::io::Result<()> {
+    let head = read_head(&mut stream).await?;
+
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, target, version) = (
+        request_line.next().unwrap_or_default(),
+        request_line.next().unwrap_or_default().to_string(),
+        request_line.next().unwrap_or_default(),
+    );
+
+    let mut credentials = None;
+    let mut headers = String::new();
+
+    for line in lines.take_while(|line| !line.is_empty()) {
+        match line.split_once(':') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("proxy-authorization") => {
+                credentials = Some(value.trim().to_string())
+            }
+            Some((name, _)) if name.trim().eq_ignore_ascii_case("proxy-connection") => {}
+            _ => headers.push_str(&format!("{line}\r\n")),
+        }
+    }
+
+    proxied.lock().unwrap().push(ProxiedRequest {
+        target: target.clone(),
+        credentials,
+    });
+
+    // Only plain HTTP is relayed, the request goes on in origin form
+    let Some(rest) = target.strip_prefix("http://") else {
+        return stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").await;
+    };
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+
+    let mut upstream = TcpStream::connect(authority).await?;
+    upstream
+        .write_all(format!("{method} {} {version}\r\n{headers}\r\n", if path.is_empty() { "/" } else { path }).as_bytes())
+        .await?;
+
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+
+    Ok(())
+}
+
+async fn relay_socks5(mut stream: TcpStream, proxied: &Mutex<Vec<ProxiedRequest>>) -> std::io::Result<()> {
+    // Greeting: version, then the authentication methods of the client
+    let mut greeting = [0; 2];
+    stream.read_exact(&mut greeting).await?;
+    let mut methods = vec![0; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let mut credentials = None;
+
+    match methods.contains(&0x02) {
+        true => {
+            stream.write_all(&[0x05, 0x02]).await?;
+
+            // Username and password subnegotiation (RFC 1929)
+            let mut header = [0; 2];
+            stream.read_exact(&mut header).await?;
+            let mut username = vec![0; header[1] as usize];
+            stream.read_exact(&mut username).await?;
+            let mut password = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut password).await?;
+
+            credentials = Some(String::from_utf8_lossy(&username).to_string());
+            stream.write_all(&[0x01, 0x00]).await?;
+        }
+        false => stream.write_all(&[0x05, 0x00]).await?,
+    }
+
+    // Request: version, command, reserved, then the address type and destination
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+
+    let host = match request[3] {
+        0x01 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        0x03 => {
+            let mut domain = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8_lossy(&domain).to_string()
+        }
+        _ => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            format!("[{}]", std::net::Ipv6Addr::from(ip))
+        }
+    };
+    let port = stream.read_u16().await?;
+    let target = format!("{host}:{port}");
+
+    proxied.lock().unwrap().push(ProxiedRequest {
+        target: target.clone(),
+        credentials,
+    });
+
+    let mut upstream = TcpStream::connect(&target).await?;
+    stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+
+    Ok(())
+}
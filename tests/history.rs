@@ -0,0 +1,74 @@
+mod common;
+
+use std::collections::HashMap;
+
+use iri_string::types::IriString;
+use rawst_dl::core::engine::{Engine, ResumeFilter};
+use rawst_dl::core::errors::RawstErr;
+use rawst_dl::core::history::HistoryManager;
+use rawst_dl::core::task::DownloadJob;
+
+use common::{test_body, test_config, ServerOptions, TestServer};
+
+/// Nothing listens on the discard port, the probe of the job fails right away
+const UNREACHABLE: &str = "http://127.0.0.1:9";
+
+fn job(url: &str) -> DownloadJob {
+    DownloadJob::new(IriString::try_from(url).unwrap(), HashMap::new(), 1)
+}
+
+#[tokio::test]
+async fn keeps_a_record_of_each_failed_batch() {
+    let config = test_config("failed-batches", 1);
+    let history_manager = HistoryManager::new(config.history_file_path.clone());
+    let engine = Engine::new(config);
+
+    assert!(engine.process_batch(vec![job(&format!("{UNREACHABLE}/one.bin"))]).await.is_err());
+    assert!(engine.process_batch(vec![job(&format!("{UNREACHABLE}/two.bin"))]).await.is_err());
+
+    let records = history_manager.get_pending().unwrap();
+    let file_names: Vec<_> = records.iter().map(|record| record.file_name.to_string_lossy().to_string()).collect();
+
+    assert_eq!(file_names, vec!["one.bin", "two.bin"]);
+    assert!(records.iter().all(|record| record.status == "Failed"));
+    assert_ne!(records[0].id, records[1].id);
+}
+
+#[test]
+fn refuses_a_record_with_a_taken_id() {
+    let config = test_config("duplicate-record", 1);
+    let history_manager = HistoryManager::new(config.history_file_path.clone());
+    let download_dir = config.download_dir.clone();
+
+    let first = job(&format!("{UNREACHABLE}/one.bin"));
+    let second = job(&format!("{UNREACHABLE}/two.bin"));
+
+    history_manager.add_failed_record(&first, download_dir.clone(), "id".to_string(), &RawstErr::Unreachable).unwrap();
+
+    let err = history_manager.add_failed_record(&second, download_dir, "id".to_string(), &RawstErr::Unreachable).unwrap_err();
+    assert!(matches!(err, RawstErr::DuplicateRecord(id) if id == "id"));
+
+    let records = history_manager.get_pending().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].file_name.to_string_lossy(), "one.bin");
+}
+
+#[tokio::test]
+async fn resumes_all_failed_records_from_the_beginning() {
+    let body = test_body(300_000);
+    let server = TestServer::start(body.clone(), ServerOptions::default()).await;
+
+    let config = test_config("resume-all-failed", 1);
+    let history_manager = HistoryManager::new(config.history_file_path.clone());
+    let download_dir = config.download_dir.clone();
+
+    // The server was down when the job was probed, nothing of it is on disk
+    let failed = job(&server.url("/late.bin"));
+    history_manager.add_failed_record(&failed, download_dir.clone(), "late".to_string(), &RawstErr::Unreachable).unwrap();
+
+    let filter = ResumeFilter { since: None, host: None };
+    Engine::new(config).process_resume_all(&filter).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("late.bin")).unwrap(), body);
+    assert_eq!(history_manager.get_record(&"late".to_string()).unwrap().unwrap().status, "Completed");
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use std::collections::HashMap;
+
+use iri_string::types::IriString;
+use rawst_dl::core::config::StorageMode;
+use rawst_dl::core::engine::Engine;
+use rawst_dl::core::task::DownloadJob;
+
+use common::{file_names, test_body, test_config, ServerOptions, TestServer};
+
+const BODY_SIZE: usize = 300_000;
+
+#[tokio::test]
+async fn resumes_interrupted_preallocated_download_from_control_file() {
+    let body = test_body(BODY_SIZE);
+    let server = TestServer::start(body.clone(), ServerOptions { drop_first: 1, ..ServerOptions::default() }).await;
+
+    let mut config = test_config("preallocated", 1);
+    config.storage = StorageMode::Preallocated;
+    let download_dir = config.download_dir.clone();
+    let cache_dir = config.cache_dir.clone();
+    let engine = Engine::new(config);
+
+    let job = DownloadJob::new(IriString::try_from(server.url("/disk.img")).unwrap(), HashMap::new(), 4);
+    assert!(engine.process_batch(vec![job]).await.is_err());
+
+    // The segments were written into the output file under a temporary name
+    assert!(!download_dir.join("disk.img").exists());
+    assert_eq!(file_names(&download_dir).len(), 1);
+    assert!(file_names(&cache_dir).iter().any(|name| name.ends_with(".ctrl")));
+
+    let interrupted = server.requests("GET");
+
+    engine.process_resume_request("auto".to_string()).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("disk.img")).unwrap(), body);
+    assert_eq!(file_names(&download_dir), vec!["disk.img"]);
+    assert!(!file_names(&cache_dir).iter().any(|name| name.ends_with(".ctrl")));
+
+    // Only the segment which was cut off is requested again, the finished ones are known from the control file
+    let resumed: Vec<_> = server.requests("GET").into_iter().skip(interrupted.len()).collect();
+    assert_eq!(resumed.len(), 1);
+
+    let segment_end = |range: &Option<String>| range.as_deref().unwrap().rsplit('-').next().unwrap().to_string();
+    assert!(interrupted.iter().any(|request| segment_end(&request.range) == segment_end(&resumed[0].range)));
+}
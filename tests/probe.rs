@@ -0,0 +1,75 @@
+mod common;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use iri_string::types::IriString;
+use rawst_dl::core::engine::Engine;
+use rawst_dl::core::history::Record;
+use rawst_dl::core::task::DownloadJob;
+
+use common::{test_body, test_config, ServerOptions, TestServer};
+
+const BODY_SIZE: usize = 300_000;
+
+fn job(url: &str, threads: usize) -> DownloadJob {
+    DownloadJob::new(IriString::try_from(url).unwrap(), HashMap::new(), threads)
+}
+
+fn records(history_file_path: &Path) -> Vec<Record> {
+    serde_json::from_str(&std::fs::read_to_string(history_file_path).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn probes_with_ranged_get_when_head_is_rejected() {
+    let body = test_body(BODY_SIZE);
+    let options = ServerOptions {
+        reject_head: true,
+        ..ServerOptions::default()
+    };
+    let server = TestServer::start(body.clone(), options).await;
+
+    let config = test_config("probe-partial", 1);
+    let history_file_path = config.history_file_path.clone();
+    let download_dir = config.download_dir.clone();
+
+    Engine::new(config).process_batch(vec![job(&server.url("/file.bin"), 4)]).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("file.bin")).unwrap(), body);
+    assert_eq!(server.requests("HEAD").len(), 1);
+
+    let requests = server.requests("GET");
+    assert_eq!(requests[0].range.as_deref(), Some("bytes=0-0"));
+
+    // The size comes from the total of `Content-Range`, so the file is split in chunks up to its last byte
+    let ranges: Vec<_> = requests[1..].iter().map(|request| request.range.clone().unwrap()).collect();
+    assert_eq!(ranges.len(), 4);
+    assert!(ranges.iter().any(|range| range.ends_with(&format!("-{}", BODY_SIZE - 1))));
+
+    assert_eq!(records(&history_file_path)[0].file_size, BODY_SIZE as u64);
+}
+
+#[tokio::test]
+async fn probes_server_which_answers_with_whole_file() {
+    let body = test_body(BODY_SIZE);
+    let options = ServerOptions {
+        reject_head: true,
+        ignore_ranges: true,
+        ..ServerOptions::default()
+    };
+    let server = TestServer::start(body.clone(), options).await;
+
+    let config = test_config("probe-whole", 1);
+    let history_file_path = config.history_file_path.clone();
+    let download_dir = config.download_dir.clone();
+
+    Engine::new(config).process_batch(vec![job(&server.url("/file.bin"), 1)]).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("file.bin")).unwrap(), body);
+
+    // The headers of the whole file are taken as they are
+    let requests = server.requests("GET");
+    assert_eq!(requests[0].range.as_deref(), Some("bytes=0-0"));
+    assert_eq!(requests.len(), 2);
+    assert_eq!(records(&history_file_path)[0].file_size, BODY_SIZE as u64);
+}
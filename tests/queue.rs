@@ -0,0 +1,38 @@
+mod common;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use chrono::Local;
+use iri_string::types::IriString;
+use rawst_dl::core::engine::Engine;
+use rawst_dl::core::queue::QueueManager;
+use rawst_dl::core::task::DownloadJob;
+
+use common::test_config;
+
+fn job(url: &str, save_as: &str) -> DownloadJob {
+    DownloadJob {
+        save_as: Some(PathBuf::from(save_as)),
+        ..DownloadJob::new(IriString::try_from(url).unwrap(), HashMap::new(), 1)
+    }
+}
+
+#[test]
+fn queues_output_paths_relative_to_the_working_directory() {
+    let config = test_config("queue-output", 1);
+    let queue_manager = QueueManager::new(config.queue_file_path());
+    let engine = Engine::new(config);
+
+    let jobs = vec![
+        job("http://127.0.0.1:9/one.bin", "nested/one.bin"),
+        job("http://127.0.0.1:9/two.bin", "two.bin"),
+    ];
+    engine.schedule_batch(jobs, Local::now(), None).unwrap();
+
+    let queue = queue_manager.get_queue().unwrap();
+    let save_as: Vec<_> = queue.iter().map(|queued| queued.job.save_as.clone().unwrap()).collect();
+
+    // A bare file name still goes to the download directory of whoever runs the queue
+    assert_eq!(save_as, vec![std::env::current_dir().unwrap().join("nested/one.bin"), PathBuf::from("two.bin")]);
+}
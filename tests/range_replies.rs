@@ -0,0 +1,169 @@
+mod common;
+
+use std::collections::HashMap;
+
+use iri_string::types::IriString;
+use rawst_dl::core::config::{Config, RemoteChangePolicy};
+use rawst_dl::core::engine::Engine;
+use rawst_dl::core::history::HistoryManager;
+use rawst_dl::core::task::DownloadJob;
+
+use common::{file_names, test_body, test_config, ChangedFile, ServerOptions, TestServer};
+
+const BODY_SIZE: usize = 300_000;
+
+fn job(url: &str, threads: usize) -> DownloadJob {
+    DownloadJob::new(IriString::try_from(url).unwrap(), HashMap::new(), threads)
+}
+
+/// Another version of the file, `size` bytes long
+fn changed_body(size: usize) -> Vec<u8> {
+    test_body(size).into_iter().map(|byte| byte ^ 0xff).collect()
+}
+
+/// Error kept in the history for the download which failed
+fn recorded_error(config: &Config) -> String {
+    let history_manager = HistoryManager::new(config.history_file_path.clone());
+    let record = history_manager.get_recent_pending().unwrap().unwrap();
+
+    assert_eq!(record.status, "Failed");
+    record.error.unwrap()
+}
+
+#[tokio::test]
+async fn downloads_sequentially_when_ranges_are_ignored() {
+    let body = test_body(BODY_SIZE);
+    let options = ServerOptions {
+        ignore_ranges: true,
+        etag: Some("\"v1\"".to_string()),
+        ..ServerOptions::default()
+    };
+    let server = TestServer::start(body.clone(), options).await;
+
+    let config = test_config("ranges-ignored", 1);
+    let download_dir = config.download_dir.clone();
+
+    Engine::new(config).process_batch(vec![job(&server.url("/file.bin"), 4)]).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("file.bin")).unwrap(), body);
+    assert_eq!(file_names(&download_dir), vec!["file.bin"]);
+
+    // The whole file came back for an unchanged `If-Range`, so it's fetched once more without ranges
+    let requests = server.requests("GET");
+    assert_eq!(requests[0].if_range.as_deref(), Some("\"v1\""));
+    assert!(requests.last().unwrap().range.is_none());
+}
+
+#[tokio::test]
+async fn fails_on_misplaced_content_range() {
+    let body = test_body(BODY_SIZE);
+    let options = ServerOptions {
+        misplaced_range: true,
+        ..ServerOptions::default()
+    };
+    let server = TestServer::start(body, options).await;
+
+    let mut config = test_config("misplaced-range", 3);
+    // Nothing changed on the server, starting over wouldn't help
+    config.on_remote_change = RemoteChangePolicy::Restart;
+    let download_dir = config.download_dir.clone();
+
+    assert!(Engine::new(config.clone()).process_batch(vec![job(&server.url("/file.bin"), 4)]).await.is_err());
+
+    assert!(!download_dir.join("file.bin").exists());
+    assert!(recorded_error(&config).starts_with("Unexpected Range"));
+
+    // Bytes at the wrong place aren't worth asking for again
+    let mut ranges: Vec<_> = server.requests("GET").into_iter().map(|request| request.range).collect();
+    let requested = ranges.len();
+    ranges.sort();
+    ranges.dedup();
+    assert_eq!(ranges.len(), requested);
+}
+
+/// The first GET is dropped halfway and the file changes before the retry, which asks for the rest with `If-Range`
+fn etag_change() -> (Vec<u8>, ServerOptions) {
+    let options = ServerOptions {
+        drop_first: 1,
+        etag: Some("\"v1\"".to_string()),
+        changed: Some(ChangedFile {
+            after: 1,
+            body: changed_body(BODY_SIZE),
+            etag: Some("\"v2\"".to_string()),
+        }),
+        ..ServerOptions::default()
+    };
+
+    (test_body(BODY_SIZE), options)
+}
+
+#[tokio::test]
+async fn aborts_when_etag_changes_under_if_range() {
+    let (body, options) = etag_change();
+    let server = TestServer::start(body, options).await;
+
+    let config = test_config("etag-abort", 2);
+    let download_dir = config.download_dir.clone();
+
+    assert!(Engine::new(config.clone()).process_batch(vec![job(&server.url("/file.bin"), 1)]).await.is_err());
+
+    assert!(!download_dir.join("file.bin").exists());
+    assert!(recorded_error(&config).starts_with("Remote File Changed"));
+
+    let retry = server.requests("GET")[1].clone();
+    assert_eq!(retry.range, Some(format!("bytes={}-{}", BODY_SIZE / 2, BODY_SIZE - 1)));
+    assert_eq!(retry.if_range.as_deref(), Some("\"v1\""));
+}
+
+#[tokio::test]
+async fn restarts_when_etag_changes_under_if_range() {
+    let (body, options) = etag_change();
+    let server = TestServer::start(body, options).await;
+
+    let mut config = test_config("etag-restart", 2);
+    config.on_remote_change = RemoteChangePolicy::Restart;
+    let download_dir = config.download_dir.clone();
+
+    Engine::new(config).process_batch(vec![job(&server.url("/file.bin"), 1)]).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("file.bin")).unwrap(), changed_body(BODY_SIZE));
+    assert_eq!(file_names(&download_dir), vec!["file.bin"]);
+}
+
+/// The file grows after the first GET, the `Content-Range` of the others tells a new total
+fn length_change() -> ServerOptions {
+    ServerOptions {
+        changed: Some(ChangedFile {
+            after: 1,
+            body: changed_body(BODY_SIZE * 2),
+            etag: None,
+        }),
+        ..ServerOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn aborts_when_content_range_total_changes() {
+    let server = TestServer::start(test_body(BODY_SIZE), length_change()).await;
+
+    let config = test_config("length-abort", 1);
+    let download_dir = config.download_dir.clone();
+
+    assert!(Engine::new(config.clone()).process_batch(vec![job(&server.url("/file.bin"), 2)]).await.is_err());
+
+    assert!(!download_dir.join("file.bin").exists());
+    assert!(recorded_error(&config).contains(&format!("{} bytes now", BODY_SIZE * 2)));
+}
+
+#[tokio::test]
+async fn restarts_when_content_range_total_changes() {
+    let server = TestServer::start(test_body(BODY_SIZE), length_change()).await;
+
+    let mut config = test_config("length-restart", 1);
+    config.on_remote_change = RemoteChangePolicy::Restart;
+    let download_dir = config.download_dir.clone();
+
+    Engine::new(config).process_batch(vec![job(&server.url("/file.bin"), 2)]).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("file.bin")).unwrap(), changed_body(BODY_SIZE * 2));
+}
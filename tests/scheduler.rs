@@ -0,0 +1,99 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rawst_dl::core::scheduler::Scheduler;
+use tokio::task::JoinHandle;
+
+/// Lets the spawned waiters run until they are all waiting again
+async fn let_waiters_run() {
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+}
+
+fn spawn_waiter(scheduler: &Arc<Scheduler>, threads: usize, priority: i32, granted: &Arc<Mutex<Vec<i32>>>) -> JoinHandle<usize> {
+    let scheduler = scheduler.clone();
+    let granted = granted.clone();
+
+    tokio::spawn(async move {
+        let lease = scheduler.acquire_connections(threads, priority).await;
+        granted.lock().unwrap().push(priority);
+
+        lease.held()
+    })
+}
+
+#[tokio::test]
+async fn serves_waiters_of_higher_priority_first() {
+    let scheduler = Arc::new(Scheduler::new(3, 2));
+    let granted = Arc::new(Mutex::new(Vec::new()));
+
+    // Of a higher priority than the waiters, nobody can take its connections away
+    let running = scheduler.acquire_connections(2, 10).await;
+
+    let low = spawn_waiter(&scheduler, 2, 1, &granted);
+    let_waiters_run().await;
+    let first_high = spawn_waiter(&scheduler, 2, 5, &granted);
+    let_waiters_run().await;
+    let second_high = spawn_waiter(&scheduler, 2, 5, &granted);
+    let_waiters_run().await;
+
+    assert!(granted.lock().unwrap().is_empty());
+
+    // Each lease is dropped as soon as it's granted, handing the connections to the next in line
+    drop(running);
+
+    for waiter in [first_high, second_high, low] {
+        assert_eq!(waiter.await.unwrap(), 2);
+    }
+
+    assert_eq!(*granted.lock().unwrap(), vec![5, 5, 1]);
+}
+
+#[tokio::test]
+async fn lower_priority_gives_back_connections_but_keeps_one() {
+    let scheduler = Arc::new(Scheduler::new(3, 4));
+    let granted = Arc::new(Mutex::new(Vec::new()));
+
+    let low = scheduler.acquire_connections(4, 0).await;
+    assert_eq!(low.held(), 4);
+
+    let high = spawn_waiter(&scheduler, 4, 5, &granted);
+    let_waiters_run().await;
+
+    // The workers of the download notice the preemption between two pieces of their chunks
+    let given_back = (0..4).filter(|_| low.take_preemption()).count();
+
+    assert_eq!(given_back, 3);
+    assert_eq!(low.held(), 1);
+
+    // Nothing more can be taken away, the waiter starts with what was given back
+    assert_eq!(high.await.unwrap(), 3);
+    assert!(!low.take_preemption());
+    assert_eq!(low.held(), 1);
+}
+
+#[tokio::test]
+async fn dropped_waiter_leaves_the_line() {
+    let scheduler = Arc::new(Scheduler::new(3, 1));
+    let granted = Arc::new(Mutex::new(Vec::new()));
+
+    let running = scheduler.acquire_connections(1, 0).await;
+
+    let dropped = spawn_waiter(&scheduler, 1, 5, &granted);
+    let_waiters_run().await;
+    let next = spawn_waiter(&scheduler, 1, 1, &granted);
+    let_waiters_run().await;
+
+    dropped.abort();
+    let_waiters_run().await;
+    drop(running);
+
+    let held = tokio::time::timeout(Duration::from_secs(5), next)
+        .await
+        .expect("the waiter behind a dropped one never got its connection")
+        .unwrap();
+
+    assert_eq!(held, 1);
+    assert_eq!(*granted.lock().unwrap(), vec![1]);
+}
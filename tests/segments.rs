@@ -0,0 +1,198 @@
+mod common;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use iri_string::types::IriString;
+use rawst_dl::core::config::{Config, HostPolicy, StorageMode, MIN_SEGMENT_SIZE};
+use rawst_dl::core::io::{restore_progress, save_control_file};
+use rawst_dl::core::task::HttpTask;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH};
+
+use common::test_config;
+
+const MIB: u64 = 1024 * 1024;
+
+fn task(total: u64, chunks: u64, download_dir: &Path) -> HttpTask {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(total));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let mut task = HttpTask::new(
+        IriString::try_from("http://127.0.0.1:9/file.bin").unwrap(),
+        PathBuf::from("file.bin"),
+        download_dir.to_path_buf(),
+        headers,
+        HashMap::new(),
+    );
+    task.min_segment_size = MIN_SEGMENT_SIZE;
+    task.calculate_chunks(chunks);
+
+    task
+}
+
+/// Offsets of every chunk in the order of the file
+fn layout(task: &HttpTask) -> Vec<(u64, u64)> {
+    let mut layout: Vec<_> = task.chunks().iter().map(|chunk| (chunk.x_offset, chunk.y_offset())).collect();
+    layout.sort();
+
+    layout
+}
+
+/// The chunks cover every byte of the file exactly once
+fn assert_covers(task: &HttpTask, total: u64) {
+    let layout = layout(task);
+
+    assert_eq!(layout.first().unwrap().0, 0);
+    assert_eq!(layout.last().unwrap().1, total - 1);
+
+    for pair in layout.windows(2) {
+        assert_eq!(pair[1].0, pair[0].1 + 1, "chunks overlap or leave a gap: {layout:?}");
+    }
+}
+
+#[test]
+fn splits_without_overlap_gap_or_small_segments() {
+    let total = 64 * MIB + 12_345;
+    let task = task(total, 2, Path::new("."));
+
+    assert!(task.next_chunk().is_some());
+    let (_, second) = task.next_chunk().unwrap();
+
+    // The first connection is done, the second one is barely started
+    let first = task.chunks()[0].clone();
+    first.downloaded.store(first.size(), Ordering::SeqCst);
+    second.downloaded.store(MIB + 7, Ordering::SeqCst);
+
+    let mut splits = 0;
+
+    while let Some((chunk_number, tail)) = task.next_chunk() {
+        assert_eq!(chunk_number, task.threads() - 1);
+        assert_covers(&task, total);
+
+        let head = task.chunks().into_iter().find(|chunk| chunk.y_offset() + 1 == tail.x_offset).unwrap();
+
+        assert!(tail.remaining() >= MIN_SEGMENT_SIZE, "tail of {} bytes", tail.remaining());
+        assert!(head.remaining() >= MIN_SEGMENT_SIZE, "head of {} bytes", head.remaining());
+        assert!(head.remaining().abs_diff(tail.remaining()) <= 1);
+
+        // The new connection finishes its part while the others move on a bit
+        tail.downloaded.store(tail.size(), Ordering::SeqCst);
+        head.downloaded.fetch_add(MIB / 3, Ordering::SeqCst);
+
+        splits += 1;
+    }
+
+    assert!(splits >= 4, "only {splits} splits");
+    assert_covers(&task, total);
+
+    // Nothing is left which could be split into two segments of the minimum size
+    assert!(task.chunks().iter().all(|chunk| chunk.remaining() < 2 * MIN_SEGMENT_SIZE));
+}
+
+#[test]
+fn never_splits_below_the_minimum_size() {
+    let total = MIN_SEGMENT_SIZE * 3 / 2;
+    let task = task(total, 2, Path::new("."));
+
+    assert!(task.next_chunk().is_some());
+    assert!(task.next_chunk().is_some());
+
+    // Both chunks are smaller than two segments of the minimum size, even though nothing is downloaded
+    assert!(task.next_chunk().is_none());
+    assert_eq!(task.threads(), 2);
+}
+
+#[test]
+fn hands_out_a_released_chunk_before_splitting() {
+    let task = task(64 * MIB, 2, Path::new("."));
+
+    let (_, first) = task.next_chunk().unwrap();
+    assert!(task.next_chunk().is_some());
+
+    first.downloaded.store(MIB, Ordering::SeqCst);
+    first.release();
+    assert!(task.has_unclaimed_chunk());
+
+    let (chunk_number, taken_over) = task.next_chunk().unwrap();
+
+    assert_eq!(chunk_number, 0);
+    assert_eq!(taken_over.current_offset(), MIB);
+    assert!(!task.has_unclaimed_chunk());
+    assert_eq!(task.threads(), 2);
+}
+
+#[test]
+fn resumes_split_chunks_from_control_file() {
+    let config = test_config("split-resume", 1);
+    let total = 64 * MIB;
+
+    let mut original = task(total, 2, &config.download_dir);
+    original.storage = StorageMode::Preallocated;
+
+    let (_, first) = original.next_chunk().unwrap();
+    let (_, second) = original.next_chunk().unwrap();
+
+    first.downloaded.store(10 * MIB, Ordering::SeqCst);
+    first.mark_persisted();
+
+    let (_, tail) = original.next_chunk().unwrap();
+    assert_eq!(tail.x_offset, 48 * MIB);
+
+    tail.downloaded.store(5 * MIB, Ordering::SeqCst);
+    tail.mark_persisted();
+
+    // Still in the file handle of its worker, nothing of it may be claimed by the control file
+    second.downloaded.store(3 * MIB, Ordering::SeqCst);
+
+    save_control_file(&original, &config.cache_dir).unwrap();
+
+    let mut resumed = task(total, 2, &config.download_dir);
+    resumed.storage = StorageMode::Preallocated;
+    resumed.timestamp = original.timestamp;
+
+    restore_progress(&mut resumed, &config.cache_dir).unwrap();
+
+    assert_eq!(layout(&resumed), vec![(0, 32 * MIB - 1), (32 * MIB, 48 * MIB - 1), (48 * MIB, 64 * MIB - 1)]);
+
+    let downloaded: Vec<_> = resumed.chunks().iter().map(|chunk| chunk.downloaded.load(Ordering::SeqCst)).collect();
+    assert_eq!(downloaded, vec![10 * MIB, 0, 5 * MIB]);
+    assert_eq!(resumed.total_downloaded.load(Ordering::SeqCst), 15 * MIB);
+
+    // Every chunk picks up where its saved progress ends
+    let offsets: Vec<_> = std::iter::from_fn(|| resumed.next_chunk())
+        .take(3)
+        .map(|(chunk_number, chunk)| (chunk_number, chunk.current_offset()))
+        .collect();
+    assert_eq!(offsets, vec![(0, 10 * MIB), (1, 32 * MIB), (2, 53 * MIB)]);
+}
+
+#[test]
+fn raises_min_segment_sizes_of_the_config() {
+    let mut config = test_config("min-segment", 1);
+    config.min_segment_size = 1024;
+    config.hosts = vec![
+        HostPolicy { pattern: "small.example.com".to_string(), min_segment_size: Some(10), ..host_policy() },
+        HostPolicy { pattern: "large.example.com".to_string(), min_segment_size: Some(8 * MIB), ..host_policy() },
+        host_policy(),
+    ];
+
+    let config = Config::from_toml(&toml::to_string(&config).unwrap());
+
+    assert_eq!(config.min_segment_size, MIN_SEGMENT_SIZE);
+
+    let host_sizes: Vec<_> = config.hosts.iter().map(|policy| policy.min_segment_size).collect();
+    assert_eq!(host_sizes, vec![Some(MIN_SEGMENT_SIZE), Some(8 * MIB), None]);
+}
+
+fn host_policy() -> HostPolicy {
+    HostPolicy {
+        pattern: "*.example.com".to_string(),
+        max_connections: None,
+        min_segment_size: None,
+        rate_limit: None,
+        proxy: None,
+        headers: HashMap::new(),
+    }
+}
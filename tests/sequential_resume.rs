@@ -0,0 +1,82 @@
+mod common;
+
+use std::collections::HashMap;
+
+use iri_string::types::IriString;
+use rawst_dl::core::engine::Engine;
+use rawst_dl::core::task::DownloadJob;
+
+use common::{file_names, test_body, test_config, ServerOptions, TestServer};
+
+const BODY_SIZE: usize = 300_000;
+
+fn job(url: &str) -> DownloadJob {
+    DownloadJob::new(IriString::try_from(url).unwrap(), HashMap::new(), 1)
+}
+
+#[tokio::test]
+async fn resumes_dropped_download_with_exact_file_name() {
+    let body = test_body(BODY_SIZE);
+    let server = TestServer::start(body.clone(), ServerOptions { drop_first: 1, ..ServerOptions::default() }).await;
+
+    let config = test_config("exact-name", 1);
+    let download_dir = config.download_dir.clone();
+    let cache_dir = config.cache_dir.clone();
+    let engine = Engine::new(config);
+
+    assert!(engine.process_batch(vec![job(&server.url("/archive.tar.gz"))]).await.is_err());
+    assert!(!download_dir.join("archive.tar.gz").exists());
+
+    engine.process_resume_request("auto".to_string()).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("archive.tar.gz")).unwrap(), body);
+    assert_eq!(file_names(&download_dir), vec!["archive.tar.gz"]);
+    assert!(!file_names(&cache_dir).iter().any(|name| name.ends_with(".ctrl")));
+
+    // Only the missing bytes are requested again
+    let resumed_range = server.requests("GET")[1].range.clone().unwrap();
+    assert_eq!(resumed_range, format!("bytes={}-{}", BODY_SIZE / 2, BODY_SIZE - 1));
+}
+
+#[tokio::test]
+async fn resumes_dropped_download_without_content_length() {
+    let body = test_body(BODY_SIZE);
+    let options = ServerOptions {
+        content_length: false,
+        drop_first: 1,
+        ..ServerOptions::default()
+    };
+    let server = TestServer::start(body.clone(), options).await;
+
+    let config = test_config("no-length", 1);
+    let download_dir = config.download_dir.clone();
+    let engine = Engine::new(config);
+
+    assert!(engine.process_batch(vec![job(&server.url("/stream.bin"))]).await.is_err());
+
+    engine.process_resume_request("auto".to_string()).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("stream.bin")).unwrap(), body);
+    assert_eq!(file_names(&download_dir), vec!["stream.bin"]);
+
+    // The end of the file is unknown so the range is left open
+    let requests = server.requests("GET");
+    let resumed_range = requests.last().unwrap().range.clone().unwrap();
+    assert!(resumed_range.ends_with('-'), "unexpected range {resumed_range}");
+    assert_ne!(resumed_range, "bytes=0-");
+}
+
+#[tokio::test]
+async fn retries_dropped_connection_within_the_same_run() {
+    let body = test_body(BODY_SIZE);
+    let server = TestServer::start(body.clone(), ServerOptions { drop_first: 2, ..ServerOptions::default() }).await;
+
+    let config = test_config("retry", 3);
+    let download_dir = config.download_dir.clone();
+    let engine = Engine::new(config);
+
+    engine.process_batch(vec![job(&server.url("/data.bin"))]).await.unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("data.bin")).unwrap(), body);
+    assert_eq!(server.requests("GET").len(), 3);
+}